@@ -0,0 +1,52 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use funcomp::ast::StmtKind;
+use funcomp::interpreter::bytecode::Program;
+use funcomp::interpreter::runtime_solver::RuntimeSolver;
+use funcomp::interpreter::Interpreter;
+use funcomp::parser::{Rule, SrcParser};
+use funcomp::RenderConfig;
+use pest::Parser;
+
+const SCRIPT: &str = "
+fn petal(a, k) = Cos(k * a) * (1 + 0.1 * Sin(8 * a));
+for T from 0 to 2 * PI step 0.0001
+    draw (100 * petal(T, 3) * Cos(T), 100 * petal(T, 3) * Sin(T));
+";
+
+fn eval(c: &mut Criterion) {
+    let pairs = SrcParser::parse(Rule::source, SCRIPT).expect("bench script parses");
+    let source = SrcParser.source(pairs).expect("bench script parses");
+    let interpreter = Interpreter::new(RenderConfig::default())
+        .accept(&source)
+        .expect("bench script folds");
+    let Some(StmtKind::Draw(_, _, _, _, x, y)) = interpreter
+        .statements
+        .iter()
+        .map(|stmt| &stmt.kind)
+        .find(|kind| matches!(kind, StmtKind::Draw(..)))
+    else {
+        panic!("bench script has no draw statement");
+    };
+    let ts: Vec<f32> = (0..62_832).map(|i| i as f32 * 0.0001).collect();
+    let environment = &interpreter.environment;
+
+    let mut group = c.benchmark_group("eval");
+    group.bench_function("tree walker", |b| {
+        b.iter(|| {
+            let mut solver = RuntimeSolver::new(ts.clone(), environment);
+            let xs = solver.solve_all(x).expect("evaluates");
+            let ys = solver.solve_all(y).expect("evaluates");
+            black_box((xs, ys))
+        })
+    });
+    group.bench_function("bytecode", |b| {
+        b.iter(|| {
+            let program = Program::compile(&[x, y], environment).expect("compiles");
+            black_box(program.eval_points(&ts))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, eval);
+criterion_main!(benches);
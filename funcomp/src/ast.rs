@@ -1,4 +1,7 @@
 use funcomp_derive::ItemKind;
+use funcomp_plot::Style;
+pub use pest::Span;
+use std::fmt::{Display, Formatter};
 
 #[macro_export]
 macro_rules! P {
@@ -12,18 +15,100 @@ macro_rules! p {
     };
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum BinOp {
     Plus,
     Minus,
     Asterisk,
     Slash,
+    Percent,
+    Caret,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum UnOp {
     Neg,
     Pos,
+    Not,
+}
+
+/// Booleans are numbers: comparisons give 1 or 0, and anything but 0 is true.
+pub fn truth(b: bool) -> f32 {
+    if b {
+        1.
+    } else {
+        0.
+    }
+}
+
+impl BinOp {
+    pub fn apply(&self, lhs: f32, rhs: f32) -> f32 {
+        match self {
+            BinOp::Plus => lhs + rhs,
+            BinOp::Minus => lhs - rhs,
+            BinOp::Asterisk => lhs * rhs,
+            BinOp::Slash => lhs / rhs,
+            BinOp::Percent => lhs.rem_euclid(rhs),
+            BinOp::Caret => lhs.powf(rhs),
+            BinOp::Lt => truth(lhs < rhs),
+            BinOp::Le => truth(lhs <= rhs),
+            BinOp::Gt => truth(lhs > rhs),
+            BinOp::Ge => truth(lhs >= rhs),
+            BinOp::Eq => truth(lhs == rhs),
+            BinOp::Ne => truth(lhs != rhs),
+            BinOp::And => truth(lhs != 0. && rhs != 0.),
+            BinOp::Or => truth(lhs != 0. || rhs != 0.),
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Plus => "+",
+            BinOp::Minus => "-",
+            BinOp::Asterisk => "*",
+            BinOp::Slash => "/",
+            BinOp::Percent => "%",
+            BinOp::Caret => "**",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+
+    /// Binding strength in the grammar, higher binds tighter.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge | BinOp::Eq | BinOp::Ne => 4,
+            BinOp::Plus | BinOp::Minus => 5,
+            BinOp::Asterisk | BinOp::Slash | BinOp::Percent => 6,
+            BinOp::Caret => 8,
+        }
+    }
+}
+
+impl UnOp {
+    pub fn apply(&self, operand: f32) -> f32 {
+        match self {
+            UnOp::Neg => -operand,
+            UnOp::Pos => operand,
+            UnOp::Not => truth(operand == 0.),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -36,28 +121,200 @@ pub struct Ident<'ast> {
     pub name: &'ast str,
 }
 
-#[derive(ItemKind, Debug)]
-pub enum Expr<'ast> {
-    Binary(Box<Expr<'ast>>, BinOp, Box<Expr<'ast>>),
-    Unary(UnOp, Box<Expr<'ast>>),
-    Call(Box<Expr<'ast>>, Vec<Expr<'ast>>),
-    Grouping(Box<Expr<'ast>>),
+#[derive(Debug, Clone)]
+pub struct Expr<'ast> {
+    pub kind: ExprKind<'ast>,
+    pub span: Span<'ast>,
+}
+
+impl<'ast> Expr<'ast> {
+    pub fn new(kind: ExprKind<'ast>, span: Span<'ast>) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(ItemKind, Debug, Clone)]
+pub enum ExprKind<'ast> {
+    Binary(P!(Expr<'ast>), BinOp, P!(Expr<'ast>)),
+    Unary(UnOp, P!(Expr<'ast>)),
+    Call(P!(Expr<'ast>), Vec<Expr<'ast>>),
+    Grouping(P!(Expr<'ast>)),
+    If(P!(Expr<'ast>), P!(Expr<'ast>), P!(Expr<'ast>)),
     Lit(Lit),
     Ident(Ident<'ast>),
 }
 
-#[derive(ItemKind, Debug)]
-pub enum Stmt<'ast> {
+#[derive(Debug, Clone)]
+pub struct Stmt<'ast> {
+    pub kind: StmtKind<'ast>,
+    pub span: Span<'ast>,
+}
+
+impl<'ast> Stmt<'ast> {
+    pub fn new(kind: StmtKind<'ast>, span: Span<'ast>) -> Self {
+        Self { kind, span }
+    }
+}
+
+/// How the parameter range of a draw statement is sampled.
+#[derive(ItemKind, Debug, Clone)]
+pub enum Step<'ast> {
+    /// Every `step` from `from` up to `to`.
+    Fixed(P!(Expr<'ast>)),
+    /// Subdivided until the curve is smooth on the canvas.
+    Auto,
+    /// Every `step` along the curve on the canvas, that is after
+    /// Scale/Rot/Origin.
+    Length(P!(Expr<'ast>)),
+}
+
+#[derive(ItemKind, Debug, Clone)]
+pub enum StmtKind<'ast> {
     Draw(
         P!(Expr<'ast>),
         P!(Expr<'ast>),
         P!(Expr<'ast>),
-        P!(Expr<'ast>),
+        Step<'ast>,
         P!(Expr<'ast>),
         P!(Expr<'ast>),
     ),
     Rot(P!(Expr<'ast>)),
     Scale(P!(Expr<'ast>), P!(Expr<'ast>)),
     Origin(P!(Expr<'ast>), P!(Expr<'ast>)),
+    Canvas(P!(Expr<'ast>), P!(Expr<'ast>)),
+    Background(P!(Expr<'ast>), P!(Expr<'ast>), P!(Expr<'ast>)),
+    Color(P!(Expr<'ast>), P!(Expr<'ast>), P!(Expr<'ast>)),
+    Size(P!(Expr<'ast>)),
+    Style(Style),
+    Let(P!(Expr<'ast>), P!(Expr<'ast>)),
+    Fn(P!(Expr<'ast>), Vec<Expr<'ast>>, P!(Expr<'ast>)),
     EOI,
 }
+
+impl Expr<'_> {
+    /// Binding strength of the outermost node, see [`BinOp::precedence`].
+    pub fn precedence(&self) -> u8 {
+        match &self.kind {
+            ExprKind::If(_, _, _) => 0,
+            ExprKind::Binary(_, op, _) => op.precedence(),
+            ExprKind::Unary(UnOp::Not, _) => 3,
+            ExprKind::Unary(_, _) => 7,
+            ExprKind::Lit(Lit::Number(n)) if n.is_sign_negative() => 7,
+            _ => 9,
+        }
+    }
+
+    /// Writes `self`, in parens if it binds looser than `min`.
+    fn fmt_operand(&self, f: &mut Formatter<'_>, min: u8) -> std::fmt::Result {
+        if self.precedence() < min {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+/// The source text of a number literal, when `span` is one that holds
+/// `value`. A literal is printed as written, since the f32 it parses into may
+/// have lost digits; folded literals keep the span of what they
+/// replaced, so they print their value instead.
+fn written<'ast>(span: Span<'ast>, value: f32) -> Option<&'ast str> {
+    let src = span.as_str();
+    let is_number = !src.is_empty() && src.bytes().all(|b| b.is_ascii_digit() || b == b'.');
+    (is_number && src.parse() == Ok(value)).then_some(src)
+}
+
+/// Prints the expression back as source, with only the parens it needs.
+impl Display for Expr<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ExprKind::Binary(lhs, op, rhs) => {
+                let prec = op.precedence();
+                let (lhs_min, rhs_min) = match op {
+                    // the base of a power is a call or primary, the exponent a unary
+                    BinOp::Caret => (9, 7),
+                    // comparisons don't chain
+                    _ if prec == 4 => (5, 5),
+                    _ => (prec, prec + 1),
+                };
+                lhs.fmt_operand(f, lhs_min)?;
+                write!(f, " {} ", op.symbol())?;
+                rhs.fmt_operand(f, rhs_min)
+            }
+            ExprKind::Unary(UnOp::Not, operand) => {
+                write!(f, "not ")?;
+                operand.fmt_operand(f, 3)
+            }
+            ExprKind::Unary(op, operand) => {
+                write!(f, "{}", if *op == UnOp::Neg { "-" } else { "+" })?;
+                if operand.precedence() == 7 {
+                    // `--` would start a comment
+                    write!(f, " ")?;
+                }
+                operand.fmt_operand(f, 7)
+            }
+            ExprKind::Call(callee, args) => {
+                write!(f, "{}(", callee)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Grouping(inner) => write!(f, "({})", inner),
+            ExprKind::If(cond, then, other) => {
+                write!(f, "if {} then {} else {}", cond, then, other)
+            }
+            ExprKind::Lit(Lit::Number(n)) => match written(self.span, *n) {
+                Some(src) => f.write_str(src),
+                None => write!(f, "{}", n),
+            },
+            ExprKind::Ident(ident) => write!(f, "{}", ident.name),
+        }
+    }
+}
+
+impl Display for Step<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Fixed(step) => write!(f, "{}", step),
+            Step::Auto => write!(f, "auto"),
+            Step::Length(step) => write!(f, "by length {}", step),
+        }
+    }
+}
+
+/// Prints the statement back as source, `EOI` prints as nothing.
+impl Display for Stmt<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            StmtKind::Draw(ident, from, to, step, x, y) => write!(
+                f,
+                "for {} from {} to {} step {} draw ({}, {});",
+                ident, from, to, step, x, y
+            ),
+            StmtKind::Rot(angle) => write!(f, "rot is {};", angle),
+            StmtKind::Scale(x, y) => write!(f, "scale is ({}, {});", x, y),
+            StmtKind::Origin(x, y) => write!(f, "origin is ({}, {});", x, y),
+            StmtKind::Canvas(width, height) => write!(f, "canvas is ({}, {});", width, height),
+            StmtKind::Background(r, g, b) => write!(f, "background is ({}, {}, {});", r, g, b),
+            StmtKind::Color(r, g, b) => write!(f, "color is ({}, {}, {});", r, g, b),
+            StmtKind::Size(size) => write!(f, "size is {};", size),
+            StmtKind::Style(style) => write!(f, "style is {};", style),
+            StmtKind::Let(ident, value) => write!(f, "let {} = {};", ident, value),
+            StmtKind::Fn(ident, params, body) => {
+                write!(f, "fn {}(", ident)?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, ") = {};", body)
+            }
+            StmtKind::EOI => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::ast::Span;
+use std::fmt::{Display, Formatter};
+
+/// The source line a diagnostic points into, captured when the diagnostic is
+/// built so it can outlive the source text.
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+    pub text: String,
+}
+
+impl Snippet {
+    pub fn new(span: Span) -> Self {
+        let start = span.start_pos();
+        let (line, col) = start.line_col();
+        let text = start.line_of().trim_end_matches(['\r', '\n']).to_string();
+        // multi-line spans are underlined up to the end of their first line
+        let len = span
+            .as_str()
+            .lines()
+            .next()
+            .map_or(1, |first| first.chars().count().max(1));
+        Self {
+            line,
+            col,
+            len,
+            text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub label: String,
+    pub snippet: Option<Snippet>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            label: String::new(),
+            snippet: None,
+        }
+    }
+
+    pub fn spanned(message: impl Into<String>, span: Span, label: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            label: label.into(),
+            snippet: Some(Snippet::new(span)),
+        }
+    }
+
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)?;
+        if let Some(snippet) = &self.snippet {
+            let gutter = snippet.line.to_string().len();
+            let pad = " ".repeat(gutter);
+            writeln!(f)?;
+            writeln!(f, "{}--> {}:{}", pad, snippet.line, snippet.col)?;
+            writeln!(f, "{} |", pad)?;
+            writeln!(f, "{} | {}", snippet.line, snippet.text)?;
+            write!(
+                f,
+                "{} | {}{}",
+                pad,
+                " ".repeat(snippet.col - 1),
+                "^".repeat(snippet.len)
+            )?;
+            if !self.label.is_empty() {
+                write!(f, " {}", self.label)?;
+            }
+        }
+        Ok(())
+    }
+}
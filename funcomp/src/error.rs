@@ -0,0 +1,49 @@
+use crate::diagnostic::Diagnostic;
+use crate::parser::Rule;
+use funcomp_plot::DrawingAreaErrorKind;
+use std::fmt::{Display, Formatter};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Parse(String),
+    Static(Vec<Diagnostic>),
+    Runtime(Diagnostic),
+    Render(String),
+    Usage(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(msg) => write!(f, "parse error: {}", msg),
+            Error::Static(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n\n")?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            Error::Runtime(diagnostic) => write!(f, "{}", diagnostic),
+            Error::Render(msg) => write!(f, "render error: {}", msg),
+            Error::Usage(msg) => write!(f, "usage error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<pest::error::Error<Rule>> for Error {
+    fn from(value: pest::error::Error<Rule>) -> Self {
+        Error::Parse(value.to_string())
+    }
+}
+
+impl<E: std::error::Error + Send + Sync> From<DrawingAreaErrorKind<E>> for Error {
+    fn from(value: DrawingAreaErrorKind<E>) -> Self {
+        Error::Render(value.to_string())
+    }
+}
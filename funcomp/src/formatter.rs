@@ -0,0 +1,249 @@
+use crate::ast::{Stmt, StmtKind};
+use crate::error::Result;
+use crate::parser::{Rule, SrcParser};
+use pest::Parser;
+
+/// Draw statements longer than this are broken before `draw`.
+pub const MAX_WIDTH: usize = 80;
+
+/// A `//` or `--` comment and where it sits in the source.
+struct Comment<'src> {
+    start: usize,
+    end: usize,
+    /// What follows the marker, trimmed.
+    text: &'src str,
+}
+
+impl Comment<'_> {
+    fn canonical(&self) -> String {
+        if self.text.is_empty() {
+            "//".into()
+        } else {
+            format!("// {}", self.text)
+        }
+    }
+}
+
+/// A line of output, `None` for a blank one.
+type Line = Option<(String, Option<String>)>;
+
+/// Prints `src` in canonical form: one statement per line with lowercase
+/// keywords, parentheses only where precedence needs them and `//`
+/// comments.
+///
+/// A comment keeps its place between statements, and one ending the line of
+/// a statement stays there, aligned with the comments of the lines around
+/// it. A comment inside a statement moves above it. Runs of blank lines
+/// shrink to one. Formatting the output again gives the same output.
+pub fn format(src: &str) -> Result<String> {
+    let source = SrcParser.source(SrcParser::parse(Rule::source, src)?)?;
+    let mut comments = comments(src).into_iter().peekable();
+
+    let mut lines: Vec<Line> = vec![];
+    // end of what was placed last
+    let mut last = 0;
+    for (i, stmt) in source.iter().enumerate() {
+        if matches!(stmt.kind, StmtKind::EOI) {
+            continue;
+        }
+        let (start, end) = (stmt.span.start(), stmt.span.end());
+        while let Some(comment) = comments.next_if(|comment| comment.start < end) {
+            // the lines of the statement don't count as a gap before a
+            // comment that moves out of it
+            let gap = comment.start.min(start);
+            separate(&mut lines, &src[last.min(gap)..gap]);
+            lines.push(Some((comment.canonical(), None)));
+            last = comment.end;
+        }
+        separate(&mut lines, &src[last.min(start)..start]);
+        last = end;
+        let mut code = statement(stmt);
+        // a statement starting in between takes the comment instead
+        let next = source
+            .get(i + 1)
+            .map_or(src.len(), |next| next.span.start());
+        let trailing = comments
+            .next_if(|comment| comment.start < next && !src[last..comment.start].contains('\n'))
+            .map(|comment| {
+                last = comment.end;
+                comment.canonical()
+            });
+        let tail = code.pop().map(|code| Some((code, trailing)));
+        lines.extend(code.into_iter().map(|code| Some((code, None))));
+        lines.extend(tail);
+    }
+    for comment in comments {
+        separate(&mut lines, &src[last..comment.start]);
+        lines.push(Some((comment.canonical(), None)));
+        last = comment.end;
+    }
+    Ok(render(&lines))
+}
+
+/// Whether `src` is already in the form [`format`] prints, as `fmt --check`
+/// requires.
+pub fn is_formatted(src: &str) -> Result<bool> {
+    Ok(format(src)? == src)
+}
+
+/// The comments of `src`, which has to parse.
+///
+/// No token has `//` or `--` in it, so once `src` parses every one of them
+/// starts a comment running to the end of the line.
+fn comments(src: &str) -> Vec<Comment<'_>> {
+    let mut comments = vec![];
+    let mut at = 0;
+    while let Some(start) = src[at..].find(['/', '-']).map(|found| at + found) {
+        let rest = &src[start..];
+        if !(rest.starts_with("//") || rest.starts_with("--")) {
+            at = start + 1;
+            continue;
+        }
+        at = start + rest.find('\n').unwrap_or(rest.len());
+        comments.push(Comment {
+            start,
+            end: at,
+            text: src[start + 2..at].trim(),
+        });
+    }
+    comments
+}
+
+/// Keeps a blank line where `gap` has one, unless nothing was output yet.
+fn separate(lines: &mut Vec<Line>, gap: &str) {
+    if gap.matches('\n').count() > 1 && lines.last().is_some_and(Option::is_some) {
+        lines.push(None);
+    }
+}
+
+fn statement(stmt: &Stmt) -> Vec<String> {
+    let line = stmt.to_string();
+    match &stmt.kind {
+        StmtKind::Draw(ident, from, to, step, x, y) if line.chars().count() > MAX_WIDTH => vec![
+            format!("for {} from {} to {} step {}", ident, from, to, step),
+            format!("    draw ({}, {});", x, y),
+        ],
+        _ => vec![line],
+    }
+}
+
+/// Joins the lines, aligning the trailing comments of consecutive lines.
+fn render(lines: &[Line]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let run = lines[i..]
+            .iter()
+            .take_while(|line| matches!(line, Some((_, Some(_)))))
+            .count();
+        if run == 0 {
+            if let Some((code, _)) = &lines[i] {
+                out.push_str(code);
+            }
+            out.push('\n');
+            i += 1;
+            continue;
+        }
+        let run = &lines[i..i + run];
+        let column = run
+            .iter()
+            .flatten()
+            .map(|(code, _)| code.chars().count())
+            .max()
+            .unwrap_or_default();
+        for (code, comment) in run.iter().flatten() {
+            let comment = comment.as_deref().unwrap_or_default();
+            out.push_str(&format!("{:<width$} {}\n", code, comment, width = column));
+        }
+        i += run.len();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    const MESSY: &str = "\
+-- header
+ORIGIN IS (320,240);   // centre
+Scale is ((100),(100)); --unit
+
+
+let   r = (1+2)*3; // three
+FN petal(a,k)=Cos(k*a)*(1+0.1*Sin(8*a));
+FOR T FROM 0 TO 2*PI STEP 0.001 DRAW (100*petal(T,3)*Cos(T), 100*petal(T, 3)*Sin(T));
+for T from 0 to 1 step auto draw (
+   T, // inside
+   -(-T));
+size is 2; style is DASHED;
+// the end
+";
+
+    #[test]
+    fn formats_into_canonical_form() {
+        assert_eq!(
+            format(MESSY).unwrap(),
+            "\
+// header
+origin is (320, 240); // centre
+scale is (100, 100);  // unit
+
+let r = (1 + 2) * 3; // three
+fn petal(a, k) = Cos(k * a) * (1 + 0.1 * Sin(8 * a));
+for T from 0 to 2 * PI step 0.001
+    draw (100 * petal(T, 3) * Cos(T), 100 * petal(T, 3) * Sin(T));
+// inside
+for T from 0 to 1 step auto draw (T, - -T);
+size is 2;
+style is dashed;
+// the end
+"
+        );
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        let formatted = format(MESSY).unwrap();
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn aligns_trailing_comments_of_consecutive_lines() {
+        assert_eq!(
+            format("rot is 1; // a\nscale is (1, 2); // b\nsize is 2;\nrot is 22; // c\n").unwrap(),
+            "rot is 1;        // a\nscale is (1, 2); // b\nsize is 2;\nrot is 22; // c\n"
+        );
+    }
+
+    #[test]
+    fn keeps_a_trailing_comment_with_the_last_statement_of_the_line() {
+        assert_eq!(
+            format("rot is 1; scale is (1,2); // about scale\n").unwrap(),
+            "rot is 1;\nscale is (1, 2); // about scale\n"
+        );
+    }
+
+    #[test]
+    fn checks_whether_already_formatted() {
+        assert!(!is_formatted(MESSY).unwrap());
+        assert!(is_formatted(&format(MESSY).unwrap()).unwrap());
+        assert!(!is_formatted("rot is 1;").unwrap());
+        assert!(is_formatted("").unwrap());
+        assert!(is_formatted("draw (T, T);").is_err());
+    }
+
+    #[test]
+    fn rejects_a_literal_out_of_range() {
+        let big = "9".repeat(40);
+        assert!(matches!(
+            format(&format!("rot is {};", big)),
+            Err(Error::Parse(message)) if message == format!("Number out of range: {}.", big)
+        ));
+        assert_eq!(
+            format(&format!("rot is {};", &big[..30])).unwrap(),
+            format!("rot is {};\n", &big[..30])
+        );
+    }
+}
@@ -0,0 +1,539 @@
+use crate::ast::{BinOp, Expr, ExprKind, Lit, UnOp};
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use crate::interpreter::environment::{Environment, IdentTy};
+use crate::interpreter::registry::BuiltinFn;
+use crate::interpreter::runtime_solver::MAX_CALL_DEPTH;
+use std::collections::{HashMap, HashSet};
+
+/// Longest [`Program`] a draw compiles into, so that funcs calling each
+/// other several times can't inline into exponentially much code.
+pub const MAX_CODE_LEN: usize = 1 << 16;
+
+/// One instruction of a [`Program`], working on a stack of values.
+#[derive(Clone, Copy)]
+pub enum Op {
+    Const(f32),
+    /// Pushes the value of the draw variable.
+    Var,
+    /// Pushes a local, i.e. an argument of an inlined user func.
+    Load(usize),
+    /// Pops into a local.
+    Store(usize),
+    Unary(UnOp),
+    Binary(BinOp),
+    /// Replaces the top `argc` values with the result of `func`.
+    Builtin(BuiltinFn, usize),
+    /// Pops and jumps to the target if the value was 0.
+    JumpIfZero(usize),
+    Jump(usize),
+}
+
+/// Expressions lowered into a flat list of [`Op`]s, so that every sample is a
+/// single loop over it instead of a walk over the boxed tree.
+///
+/// Identifiers are resolved and user funcs are inlined while compiling, the
+/// way [`RuntimeSolver`](super::runtime_solver::RuntimeSolver) would resolve
+/// them, which leaves nothing that can fail during evaluation. The
+/// [`repeats`] of the expressions are evaluated once and kept in locals.
+pub struct Program {
+    pub code: Vec<Op>,
+    /// How many locals the inlined calls need.
+    pub locals: usize,
+    /// Values left on the stack by a run, one per compiled expression.
+    pub outputs: usize,
+}
+
+struct Compiler<'env, 'ast> {
+    environment: &'env Environment<'ast>,
+    code: Vec<Op>,
+    locals: usize,
+    /// Locals of the params of the user funcs being inlined.
+    frames: Vec<HashMap<&'ast str, usize>>,
+    /// Canonical forms of the [`repeats`] being compiled.
+    shared: HashSet<String>,
+    /// Locals holding the values of `shared` computed so far, by canonical
+    /// form.
+    values: HashMap<String, usize>,
+}
+
+/// The parts of `exprs` whose value is computed already when evaluation
+/// reaches them, in order and leaving out those inside another.
+///
+/// A value computed in a branch of an `if` only counts within the branch.
+/// Literals and idents are cheaper to evaluate again than to keep, and the
+/// bodies of user funcs are left out.
+pub fn repeats<'e, 'ast>(exprs: &[&'e Expr<'ast>]) -> Vec<&'e Expr<'ast>> {
+    fn walk<'e, 'ast>(
+        expr: &'e Expr<'ast>,
+        seen: &mut HashSet<String>,
+        repeats: &mut Vec<&'e Expr<'ast>>,
+    ) {
+        match &expr.kind {
+            ExprKind::Lit(_) | ExprKind::Ident(_) => return,
+            ExprKind::Grouping(inner) => return walk(inner, seen, repeats),
+            _ => {}
+        }
+        let key = expr.to_string();
+        if seen.contains(&key) {
+            repeats.push(expr);
+            return;
+        }
+        match &expr.kind {
+            ExprKind::Binary(lhs, _, rhs) => {
+                walk(lhs, seen, repeats);
+                walk(rhs, seen, repeats);
+            }
+            ExprKind::Unary(_, operand) => walk(operand, seen, repeats),
+            ExprKind::Call(_, args) => args.iter().for_each(|arg| walk(arg, seen, repeats)),
+            ExprKind::If(cond, then, other) => {
+                walk(cond, seen, repeats);
+                walk(then, &mut seen.clone(), repeats);
+                walk(other, &mut seen.clone(), repeats);
+            }
+            ExprKind::Lit(_) | ExprKind::Ident(_) | ExprKind::Grouping(_) => {}
+        }
+        seen.insert(key);
+    }
+    let mut seen = HashSet::new();
+    let mut repeats = vec![];
+    for expr in exprs {
+        walk(expr, &mut seen, &mut repeats);
+    }
+    repeats
+}
+
+impl Program {
+    /// Compiles `exprs` into one program leaving their values on the stack in
+    /// order.
+    pub fn compile<'ast>(exprs: &[&Expr<'ast>], environment: &Environment<'ast>) -> Result<Self> {
+        let mut compiler = Compiler {
+            environment,
+            code: vec![],
+            locals: 0,
+            frames: vec![],
+            shared: repeats(exprs).iter().map(ToString::to_string).collect(),
+            values: HashMap::new(),
+        };
+        for expr in exprs {
+            compiler.expr(expr)?;
+        }
+        Ok(Self {
+            code: compiler.code,
+            locals: compiler.locals,
+            outputs: exprs.len(),
+        })
+    }
+
+    /// Runs the program once with the draw variable set to `t`.
+    pub fn run(&self, t: f32, stack: &mut Vec<f32>, locals: &mut [f32]) {
+        let mut pc = 0;
+        while let Some(op) = self.code.get(pc) {
+            pc += 1;
+            match *op {
+                Op::Const(value) => stack.push(value),
+                Op::Var => stack.push(t),
+                Op::Load(local) => stack.push(locals[local]),
+                Op::Store(local) => locals[local] = pop(stack),
+                Op::Unary(op) => {
+                    let operand = pop(stack);
+                    stack.push(op.apply(operand));
+                }
+                Op::Binary(op) => {
+                    let rhs = pop(stack);
+                    let lhs = pop(stack);
+                    stack.push(op.apply(lhs, rhs));
+                }
+                Op::Builtin(func, argc) => {
+                    let at = stack.len().saturating_sub(argc);
+                    let result = func(&stack[at..]);
+                    stack.truncate(at);
+                    stack.push(result);
+                }
+                Op::JumpIfZero(target) => {
+                    if pop(stack) == 0. {
+                        pc = target;
+                    }
+                }
+                Op::Jump(target) => pc = target,
+            }
+        }
+    }
+
+    /// Evaluates a program compiled from an `(x, y)` pair at every `t`.
+    ///
+    /// With the `parallel` feature, `ts` is split into chunks evaluated on
+    /// separate threads; every sample is independent of the others, so the
+    /// result is the same as evaluating them in order.
+    pub fn eval_points(&self, ts: &[f32]) -> Vec<(f32, f32)> {
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            if ts.len() > PARALLEL_CHUNK {
+                return ts
+                    .par_chunks(PARALLEL_CHUNK)
+                    .flat_map_iter(|chunk| self.eval_chunk(chunk))
+                    .collect();
+            }
+        }
+        self.eval_chunk(ts)
+    }
+
+    fn eval_chunk(&self, ts: &[f32]) -> Vec<(f32, f32)> {
+        let mut stack = Vec::with_capacity(16);
+        let mut locals = vec![0.; self.locals];
+        ts.iter()
+            .map(|t| {
+                self.run(*t, &mut stack, &mut locals);
+                let y = pop(&mut stack);
+                let x = pop(&mut stack);
+                (x, y)
+            })
+            .collect()
+    }
+}
+
+/// Samples per task when evaluating in parallel, small batches such as the
+/// rounds of `step auto` stay on the calling thread.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK: usize = 4096;
+
+/// The compiler keeps the stack balanced, so this never comes up empty for a
+/// compiled program.
+fn pop(stack: &mut Vec<f32>) -> f32 {
+    stack.pop().unwrap_or(f32::NAN)
+}
+
+/// The draw of a test source, folded and compiled the way a render would.
+#[cfg(test)]
+pub(crate) struct Compiled<'ast> {
+    pub environment: Environment<'ast>,
+    pub x: Expr<'ast>,
+    pub y: Expr<'ast>,
+    pub program: Result<Program>,
+}
+
+#[cfg(test)]
+impl<'ast> Compiled<'ast> {
+    /// Compiles the first draw in `src`.
+    pub fn draw(src: &'ast str) -> Self {
+        use crate::ast::StmtKind;
+        use crate::interpreter::constant_folder::ConstantFolder;
+        use crate::parser::{Rule, SrcParser};
+        use pest::Parser;
+
+        let pairs = SrcParser::parse(Rule::source, src).unwrap();
+        let source = SrcParser.source(pairs).unwrap();
+        let mut environment = Environment::default();
+        let statements = ConstantFolder::new(&mut environment).fold(&source).unwrap();
+        let (x, y) = statements
+            .into_iter()
+            .find_map(|stmt| match stmt.kind {
+                StmtKind::Draw(_, _, _, _, x, y) => Some((*x, *y)),
+                _ => None,
+            })
+            .unwrap();
+        let program = Program::compile(&[&x, &y], &environment);
+        Self {
+            environment,
+            x,
+            y,
+            program,
+        }
+    }
+}
+
+impl<'env, 'ast> Compiler<'env, 'ast> {
+    fn expr(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        let key = match &expr.kind {
+            _ if self.shared.is_empty() => None,
+            ExprKind::Lit(_) | ExprKind::Ident(_) | ExprKind::Grouping(_) => None,
+            _ => Some(expr.to_string()).filter(|key| self.shared.contains(key)),
+        };
+        let Some(key) = key else {
+            return self.compute(expr);
+        };
+        if let Some(local) = self.values.get(&key) {
+            self.code.push(Op::Load(*local));
+            return Ok(());
+        }
+        self.compute(expr)?;
+        let local = self.locals;
+        self.locals += 1;
+        self.code.push(Op::Store(local));
+        self.code.push(Op::Load(local));
+        self.values.insert(key, local);
+        Ok(())
+    }
+
+    /// Compiles the evaluation of `expr` itself, rather than reusing it.
+    fn compute(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        match &expr.kind {
+            ExprKind::Binary(lhs, op, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                self.code.push(Op::Binary(*op));
+            }
+            ExprKind::Unary(op, operand) => {
+                self.expr(operand)?;
+                self.code.push(Op::Unary(*op));
+            }
+            ExprKind::Call(callee, args) => self.call(expr, callee, args)?,
+            ExprKind::Grouping(inner) => self.expr(inner)?,
+            ExprKind::If(cond, then, other) => {
+                self.expr(cond)?;
+                let to_other = self.code.len();
+                self.code.push(Op::JumpIfZero(0));
+                // the values computed in a branch are gone once it's left
+                let values = self.values.clone();
+                self.expr(then)?;
+                self.values = values.clone();
+                let to_end = self.code.len();
+                self.code.push(Op::Jump(0));
+                self.code[to_other] = Op::JumpIfZero(self.code.len());
+                self.expr(other)?;
+                self.values = values;
+                self.code[to_end] = Op::Jump(self.code.len());
+            }
+            ExprKind::Lit(Lit::Number(value)) => self.code.push(Op::Const(*value)),
+            ExprKind::Ident(ident) => {
+                let name = ident.name;
+                if let Some(local) = self.frames.last().and_then(|frame| frame.get(name)) {
+                    self.code.push(Op::Load(*local));
+                    return Ok(());
+                }
+                if let Some(value) = self.environment.constants.get(name) {
+                    self.code.push(Op::Const(*value));
+                    return Ok(());
+                }
+                match self.environment.lookup.get(name) {
+                    Some(IdentTy::Var) => self.code.push(Op::Var),
+                    _ => {
+                        return Err(Error::Runtime(Diagnostic::spanned(
+                            format!("Unresolved ident: {}.", name),
+                            expr.span,
+                            "unresolved at runtime",
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, expr: &Expr<'ast>, callee: &Expr<'ast>, args: &[Expr<'ast>]) -> Result<()> {
+        let ExprKind::Ident(ident) = &callee.kind else {
+            return Err(Error::Runtime(Diagnostic::spanned(
+                "Expect an ident as callee.",
+                callee.span,
+                "expect an ident",
+            )));
+        };
+        for arg in args {
+            self.expr(arg)?;
+        }
+        let environment = self.environment;
+        if let Some(function) = environment.functions.get(ident.name) {
+            if self.frames.len() >= MAX_CALL_DEPTH {
+                return Err(Error::Runtime(Diagnostic::spanned(
+                    "Call depth exceeded.",
+                    expr.span,
+                    "too deeply nested",
+                )));
+            }
+            if self.code.len() >= MAX_CODE_LEN {
+                return Err(Error::Runtime(Diagnostic::spanned(
+                    "Draw compiles into too much code.",
+                    expr.span,
+                    format!("inlines past {} ops", MAX_CODE_LEN),
+                )));
+            }
+            if function.params.len() != args.len() {
+                return Err(Error::Runtime(Diagnostic::spanned(
+                    format!(
+                        "{} takes {} argument(s) but {} were supplied.",
+                        ident.name,
+                        function.params.len(),
+                        args.len()
+                    ),
+                    expr.span,
+                    "wrong number of arguments",
+                )));
+            }
+            // the arguments are on the stack in order, so the last one is
+            // stored first
+            let first = self.locals;
+            self.locals += args.len();
+            for local in (first..self.locals).rev() {
+                self.code.push(Op::Store(local));
+            }
+            let frame = function.params.iter().copied().zip(first..).collect();
+            self.frames.push(frame);
+            // the same text reads other params in the body
+            let shared = std::mem::take(&mut self.shared);
+            let values = std::mem::take(&mut self.values);
+            let body = self.expr(&function.body);
+            self.shared = shared;
+            self.values = values;
+            self.frames.pop();
+            return body;
+        }
+        match environment.registry.get(ident.name) {
+            Some(builtin) if builtin.arity.accepts(args.len()) => {
+                self.code.push(Op::Builtin(builtin.func, args.len()));
+                Ok(())
+            }
+            _ => Err(Error::Runtime(Diagnostic::spanned(
+                "Invalid func name during runtime.",
+                callee.span,
+                "unknown func",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::runtime_solver::RuntimeSolver;
+
+    /// Samples on both sides of 0, so signs and zeros come up.
+    fn ts() -> Vec<f32> {
+        (-20..=20).map(|i| i as f32 * 0.37).collect()
+    }
+
+    /// Points as the bits of their coordinates.
+    type Bits = Vec<(u32, u32)>;
+
+    /// The points of the draw in `src` at [`ts`], once from a compiled
+    /// [`Program`] and once from a [`RuntimeSolver`], as bits so NaNs compare.
+    fn both(src: &str) -> (Bits, Bits) {
+        let Compiled {
+            environment,
+            x,
+            y,
+            program,
+        } = Compiled::draw(src);
+        let bits = |(x, y): (f32, f32)| (x.to_bits(), y.to_bits());
+        let compiled = program
+            .unwrap()
+            .eval_points(&ts())
+            .into_iter()
+            .map(bits)
+            .collect();
+        let xs = RuntimeSolver::new(ts(), &environment)
+            .solve_all(&x)
+            .unwrap();
+        let ys = RuntimeSolver::new(ts(), &environment)
+            .solve_all(&y)
+            .unwrap();
+        let solved = xs.into_iter().zip(ys).map(bits).collect();
+        (compiled, solved)
+    }
+
+    fn assert_same(src: &str) {
+        let (compiled, solved) = both(src);
+        assert_eq!(compiled, solved, "{}", src);
+    }
+
+    #[test]
+    fn branches_like_the_solver() {
+        assert_same("for T from 0 to 1 step 0.1 draw (if T > 0 then T else -T, T);");
+        assert_same(
+            "for T from 0 to 1 step 0.1 draw (if T < -1 or T > 1 then 0 else 1, \
+             if not T >= 0 then if T > -2 then 1 else 2 else 3);",
+        );
+    }
+
+    #[test]
+    fn inlines_user_funcs_like_the_solver() {
+        assert_same(
+            "fn sq(a) = a * a; fn both(a, b) = sq(a) - sq(b) / 2; \
+             for T from 0 to 1 step 0.1 draw (both(T, Sin(T)), sq(both(1, T)));",
+        );
+        // an impure func reads the draw variable through its body
+        assert_same(
+            "fn w() = 3 * T; fn shift(a) = a + w(); \
+             for T from 0 to 1 step 0.1 draw (shift(T), w() * shift(2));",
+        );
+    }
+
+    #[test]
+    fn calls_builtins_like_the_solver() {
+        assert_same(
+            "for T from 0 to 1 step 0.1 draw (Atan2(T, 1 - T) + Hypot(T, 2), \
+             Pow(T, 3) - Mod(T, 1.5));",
+        );
+        assert_same(
+            "for T from 0 to 1 step 0.1 draw (Clamp(T, -1, 1) + Min(T, 0, Sin(T)), \
+             Max(Cos(T), T, Sqrt(T), Ln(T)));",
+        );
+    }
+
+    #[test]
+    fn evaluates_repeats_once() {
+        let src = "for T from 0 to 1 step 0.1 draw (Cos(T * 2) * Sin(T), Cos(T * 2) + T * 2);";
+        let program = Compiled::draw(src).program.unwrap();
+        let calls = program
+            .code
+            .iter()
+            .filter(|op| matches!(op, Op::Builtin(_, _)))
+            .count();
+        // Cos and Sin, the second Cos is loaded
+        assert_eq!(calls, 2);
+        assert_same(src);
+        assert_same(
+            "fn sq(a) = a * a; fn w() = Sin(T) * Sin(T); \
+             for T from 0 to 1 step 0.1 draw (sq(T + 1) + sq(T + 1) * w(), \
+             if T > 0 then sq(T + 1) + Sin(T) * Sin(T) else Sin(T) * Sin(T) - w());",
+        );
+    }
+
+    #[test]
+    fn caps_the_code_of_funcs_calling_each_other() {
+        // each func doubles the code of the one it calls
+        let mut src = String::from("fn f0(a) = Sin(a);");
+        for i in 1..40 {
+            src += &format!(" fn f{i}(a) = f{}(a) + f{}(a * 2);", i - 1, i - 1);
+        }
+        src += " for T from 0 to 1 step 0.1 draw (f39(T), T);";
+        let Err(Error::Runtime(diagnostic)) = Compiled::draw(&src).program else {
+            panic!("compiled past the cap");
+        };
+        assert_eq!(diagnostic.message, "Draw compiles into too much code.");
+    }
+
+    #[test]
+    fn applies_operators_like_the_solver() {
+        assert_same("for T from 0 to 1 step 0.1 draw (T % 2 - T % -0.5, -T % 3);");
+        assert_same("for T from 0 to 1 step 0.1 draw (T ** 2 ** 0.5, (T - 1) ** 3 / T);");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn evaluates_chunks_in_parallel_like_in_order() {
+        use crate::parser::{Rule, SrcParser};
+        use pest::Parser;
+
+        let pairs = SrcParser::parse(Rule::expression, "Sin(T * 3) % 1 + T ** 2").unwrap();
+        let x = SrcParser.expression(pairs).unwrap();
+        let pairs = SrcParser::parse(Rule::expression, "if T > 50 then Cos(T) else T").unwrap();
+        let y = SrcParser.expression(pairs).unwrap();
+        let environment = Environment::default();
+        let program = Program::compile(&[&x, &y], &environment).unwrap();
+        // enough samples for several chunks, the last one cut short
+        let ts: Vec<f32> = (0..PARALLEL_CHUNK * 3 + 17)
+            .map(|i| i as f32 * 0.01)
+            .collect();
+        let bits = |points: Vec<(f32, f32)>| -> Bits {
+            points
+                .into_iter()
+                .map(|(x, y)| (x.to_bits(), y.to_bits()))
+                .collect()
+        };
+        assert_eq!(
+            bits(program.eval_points(&ts)),
+            bits(program.eval_chunk(&ts))
+        );
+    }
+}
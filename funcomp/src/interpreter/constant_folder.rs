@@ -0,0 +1,606 @@
+use crate::ast::{truth, BinOp, Expr, ExprKind, Lit, Span, Step, Stmt, StmtKind, UnOp};
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use crate::interpreter::bytecode::repeats;
+use crate::interpreter::derivative::{differentiate, DERIVATIVE};
+use crate::interpreter::environment::{Environment, Function, IdentTy};
+use crate::interpreter::runtime_solver::RuntimeSolver;
+use crate::interpreter::visit::{walk_expr, walk_stmt, Visitor};
+use crate::p;
+use std::fmt::{Display, Formatter};
+use std::ptr;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChangeKind {
+    /// Evaluated into a literal.
+    Folded,
+    /// Rewritten by an algebraic identity, or an `if` reduced to the branch
+    /// its folded condition picks.
+    Simplified,
+    /// A call of [`DERIVATIVE`] replaced by the derivative.
+    Derived,
+    /// Computed already when a draw reaches it, so the compiled program
+    /// loads the value instead, see [`repeats`].
+    Reused,
+}
+
+/// A rewrite made by the [`ConstantFolder`].
+#[derive(Debug, Clone)]
+pub struct Change<'ast> {
+    pub kind: ChangeKind,
+    /// What was rewritten.
+    pub span: Span<'ast>,
+    /// What it was rewritten into, as source.
+    pub result: String,
+}
+
+impl Display for Change<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let before = self.span.as_str().trim();
+        match self.kind {
+            ChangeKind::Folded => write!(f, "folded `{}` into `{}`", before, self.result),
+            ChangeKind::Simplified => {
+                write!(f, "simplified `{}` into `{}`", before, self.result)
+            }
+            ChangeKind::Derived => write!(f, "derived `{}` into `{}`", before, self.result),
+            ChangeKind::Reused => write!(f, "reused the value of `{}`", self.result),
+        }
+    }
+}
+
+/// Folds statements into a new AST. Constants and `let` bindings are
+/// substituted, operators on literals and calls of pure funcs with literal
+/// arguments are evaluated, and names that can't be, like `T` and params, are
+/// kept. `let` and `fn` only add to the environment.
+///
+/// On top of that `x * 1`, `1 * x`, `x / 1`, `x ** 1`, `x + 0`, `0 + x`,
+/// `x - 0`, `- -x` and `+x` become `x`, and `0 * x` becomes 0, taking `x` to
+/// be finite. Every rewrite is reported in [`ConstantFolder::changes`], as is
+/// every part of a draw that its compiled program evaluates once and reuses.
+pub struct ConstantFolder<'env, 'ast> {
+    pub environment: &'env mut Environment<'ast>,
+    pub changes: Vec<Change<'ast>>,
+    stack: Vec<Expr<'ast>>,
+    statements: Vec<Stmt<'ast>>,
+    /// Params of the `fn` being folded, which hide any outer name.
+    params: Vec<&'ast str>,
+}
+
+impl<'env, 'ast> ConstantFolder<'env, 'ast> {
+    pub fn new(environment: &'env mut Environment<'ast>) -> Self {
+        Self {
+            environment,
+            changes: vec![],
+            stack: vec![],
+            statements: vec![],
+            params: vec![],
+        }
+    }
+
+    /// Returns the folded `src`, without its `let` and `fn`.
+    pub fn fold(&mut self, src: &[Stmt<'ast>]) -> Result<Vec<Stmt<'ast>>> {
+        self.stack.clear();
+        let folded = src.iter().try_for_each(|stmt| self.visit_stmt(stmt));
+        let statements = std::mem::take(&mut self.statements);
+        folded.map(|_| statements)
+    }
+
+    pub fn fold_expr(&mut self, expr: &Expr<'ast>) -> Result<Expr<'ast>> {
+        self.stack.clear();
+        self.visit_expr(expr)?;
+        self.pop()
+    }
+
+    /// The derivative of `expr` in `var`, both folded.
+    pub fn derive(&mut self, expr: &Expr<'ast>, var: &str) -> Result<Expr<'ast>> {
+        self.stack.clear();
+        self.fold_derivative(expr, var)
+    }
+
+    fn fold_derivative(&mut self, expr: &Expr<'ast>, var: &str) -> Result<Expr<'ast>> {
+        self.visit_expr(expr)?;
+        let folded = self.pop()?;
+        let derivative = differentiate(&folded, var, self.environment)?;
+        self.visit_expr(&derivative)?;
+        self.pop()
+    }
+
+    fn fold_fn(
+        &mut self,
+        ident: &Expr<'ast>,
+        params: &[Expr<'ast>],
+        body: &Expr<'ast>,
+    ) -> Result<()> {
+        let params: Vec<&'ast str> = params
+            .iter()
+            .filter_map(|param| match &param.kind {
+                ExprKind::Ident(ident) => Some(ident.name),
+                _ => None,
+            })
+            .collect();
+        let outer: Vec<_> = params
+            .iter()
+            .map(|param| {
+                let lookup = &mut self.environment.lookup;
+                (*param, lookup.insert(param.to_string(), IdentTy::Var))
+            })
+            .collect();
+        self.params = params.clone();
+        let body = self.visit_expr(body).and_then(|_| self.pop());
+        self.params.clear();
+        for (param, ty) in outer {
+            match ty {
+                Some(ty) => self.environment.lookup.insert(param.into(), ty),
+                None => self.environment.lookup.remove(param),
+            };
+        }
+        let body = body?;
+        if let ExprKind::Ident(ident) = &ident.kind {
+            let pure = !self.environment.depends_on_var(&body, &params);
+            self.environment
+                .define(ident.name, Function { params, body, pure });
+        }
+        Ok(())
+    }
+
+    /// Pushes `result` in place of the expression at `span`, and reports it
+    /// unless it was evaluated or simplified into what it reads already.
+    /// Changes inside `span` are left out of the report, only the outermost
+    /// one is kept.
+    fn rewrite(&mut self, kind: ChangeKind, span: Span<'ast>, result: Expr<'ast>) {
+        let text = result.to_string();
+        self.stack.push(result);
+        if text == span.as_str().trim() {
+            return;
+        }
+        self.changes.retain(|change| {
+            !(ptr::eq(change.span.get_input(), span.get_input())
+                && span.start() <= change.span.start()
+                && change.span.end() <= span.end())
+        });
+        self.changes.push(Change {
+            kind,
+            span,
+            result: text,
+        });
+    }
+
+    fn number(&mut self, kind: ChangeKind, span: Span<'ast>, value: f32) {
+        self.rewrite(
+            kind,
+            span,
+            Expr::new(ExprKind::lit(Lit::Number(value)), span),
+        );
+    }
+
+    fn fold_binary(&mut self, op: BinOp, span: Span<'ast>) -> Result<()> {
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        let number = |expr: &Expr| match expr.kind {
+            ExprKind::Lit(Lit::Number(n)) => Some(n),
+            _ => None,
+        };
+        let lit = |value| Expr::new(ExprKind::lit(Lit::Number(value)), span);
+        let (kind, result) = match (number(&lhs), op, number(&rhs)) {
+            (Some(l), _, Some(r)) => (ChangeKind::Folded, lit(op.apply(l, r))),
+            // the lhs alone decides the result
+            (Some(0.), BinOp::And, _) => (ChangeKind::Folded, lit(truth(false))),
+            (Some(l), BinOp::Or, _) if l != 0. => (ChangeKind::Folded, lit(truth(true))),
+            (Some(0.), BinOp::Asterisk, _) | (_, BinOp::Asterisk, Some(0.)) => {
+                (ChangeKind::Simplified, lit(0.))
+            }
+            (Some(1.), BinOp::Asterisk, _) => (ChangeKind::Simplified, rhs),
+            (Some(0.), BinOp::Plus, _) => (ChangeKind::Simplified, rhs),
+            (_, BinOp::Asterisk | BinOp::Slash | BinOp::Caret, Some(1.)) => {
+                (ChangeKind::Simplified, lhs)
+            }
+            (_, BinOp::Plus | BinOp::Minus, Some(0.)) => (ChangeKind::Simplified, lhs),
+            _ => {
+                self.stack
+                    .push(Expr::new(ExprKind::binary(p!(lhs), op, p!(rhs)), span));
+                return Ok(());
+            }
+        };
+        self.rewrite(kind, span, result);
+        Ok(())
+    }
+
+    fn fold_unary(&mut self, op: UnOp, span: Span<'ast>) -> Result<()> {
+        let operand = self.pop()?;
+        match (op, operand.kind) {
+            (_, ExprKind::Lit(Lit::Number(lit))) => {
+                self.number(ChangeKind::Folded, span, op.apply(lit))
+            }
+            (UnOp::Pos, kind) => {
+                self.rewrite(ChangeKind::Simplified, span, Expr::new(kind, operand.span))
+            }
+            (UnOp::Neg, ExprKind::Unary(UnOp::Neg, inner)) => {
+                self.rewrite(ChangeKind::Simplified, span, *inner)
+            }
+            (op, kind) => {
+                let operand = Expr::new(kind, operand.span);
+                self.stack
+                    .push(Expr::new(ExprKind::unary(op, p!(operand)), span));
+            }
+        }
+        Ok(())
+    }
+
+    fn fold_call(&mut self, arity: usize, span: Span<'ast>) -> Result<()> {
+        let at = self.stack.len().saturating_sub(arity);
+        let args = self.stack.split_off(at);
+        let callee = self.pop()?;
+        let ExprKind::Ident(ident) = &callee.kind else {
+            return Err(Error::Runtime(Diagnostic::spanned(
+                "Expect an ident as callee.",
+                callee.span,
+                "expect an ident",
+            )));
+        };
+        let name = ident.name;
+        let lits: Option<Vec<f32>> = args
+            .iter()
+            .map(|arg| match arg.kind {
+                ExprKind::Lit(Lit::Number(lit)) => Some(lit),
+                _ => None,
+            })
+            .collect();
+        let function = self.environment.functions.get(name);
+        match (lits, function) {
+            (Some(lits), Some(function)) if function.pure => {
+                let result = RuntimeSolver::new(vec![], self.environment).call(name, lits, span)?;
+                self.number(ChangeKind::Folded, span, result)
+            }
+            (Some(lits), None) => {
+                let result = self.environment.registry.call(name, &lits).ok_or_else(|| {
+                    Error::Runtime(Diagnostic::spanned(
+                        "Invalid internal func.",
+                        callee.span,
+                        "unknown func",
+                    ))
+                })?;
+                self.number(ChangeKind::Folded, span, result)
+            }
+            _ => self
+                .stack
+                .push(Expr::new(ExprKind::call(p!(callee), args), span)),
+        }
+        Ok(())
+    }
+
+    fn fold_ident(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        let ExprKind::Ident(ident) = &expr.kind else {
+            return Ok(());
+        };
+        let (name, span) = (ident.name, expr.span);
+        if self.params.contains(&name) {
+            self.stack.push(expr.clone());
+            return Ok(());
+        }
+        if let Some(value) = self.environment.bindings.get(name) {
+            let value = Expr::new(value.kind.clone(), span);
+            if matches!(value.kind, ExprKind::Lit(_)) {
+                self.rewrite(ChangeKind::Folded, span, value);
+            } else {
+                self.stack.push(value);
+            }
+            return Ok(());
+        }
+        if let Some(value) = self.environment.constants.get(name) {
+            self.number(ChangeKind::Folded, span, *value);
+            return Ok(());
+        }
+        if !self.environment.lookup.contains_key(name) {
+            return Err(Error::Runtime(Diagnostic::spanned(
+                format!("Unresolved ident: {}.", name),
+                span,
+                "unknown ident",
+            )));
+        }
+        self.stack.push(expr.clone());
+        Ok(())
+    }
+
+    /// Folds `expr` whatever was folded before.
+    fn fold_node(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        if let ExprKind::If(cond, then, other) = &expr.kind {
+            self.visit_expr(cond)?;
+            let cond = self.pop()?;
+            if let ExprKind::Lit(Lit::Number(lit)) = cond.kind {
+                // a folded condition selects its branch at compile time
+                self.visit_expr(if lit != 0. { then } else { other })?;
+                let branch = self.pop()?;
+                self.rewrite(ChangeKind::Simplified, expr.span, branch);
+                return Ok(());
+            }
+            self.visit_expr(then)?;
+            let then = self.pop()?;
+            self.visit_expr(other)?;
+            let other = self.pop()?;
+            self.stack.push(Expr::new(
+                ExprKind::r#if(p!(cond), p!(then), p!(other)),
+                expr.span,
+            ));
+            return Ok(());
+        }
+        if let ExprKind::Ident(_) = &expr.kind {
+            return self.fold_ident(expr);
+        }
+        if let ExprKind::Call(callee, args) = &expr.kind {
+            if matches!(&callee.kind, ExprKind::Ident(ident) if ident.name == DERIVATIVE) {
+                let var = match args.as_slice() {
+                    [_] => "T",
+                    [_, Expr {
+                        kind: ExprKind::Ident(ident),
+                        ..
+                    }] => ident.name,
+                    _ => {
+                        return Err(Error::Runtime(Diagnostic::spanned(
+                            "Expect an expr and the ident to derive it in.",
+                            expr.span,
+                            "expect D(expr) or D(expr, ident)",
+                        )))
+                    }
+                };
+                // how the derivative came about is no news
+                let mark = self.changes.len();
+                let derivative = self.fold_derivative(&args[0], var)?;
+                self.changes.truncate(mark);
+                self.rewrite(ChangeKind::Derived, expr.span, derivative);
+                return Ok(());
+            }
+        }
+        walk_expr(self, expr)?;
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Binary(_, op, _) => self.fold_binary(*op, span)?,
+            ExprKind::Unary(op, _) => self.fold_unary(*op, span)?,
+            ExprKind::Call(_, args) => self.fold_call(args.len(), span)?,
+            ExprKind::Grouping(_) => {
+                let inner = self.pop()?;
+                self.stack.push(inner);
+            }
+            ExprKind::Lit(lit) => self.stack.push(Expr::new(ExprKind::lit(lit.clone()), span)),
+            // folded before the walk
+            ExprKind::If(_, _, _) | ExprKind::Ident(_) => {}
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Expr<'ast>> {
+        self.stack
+            .pop()
+            .ok_or_else(|| Error::Runtime(Diagnostic::new("Calculation stack underflow.")))
+    }
+}
+
+impl<'env, 'ast> Visitor<'ast> for ConstantFolder<'env, 'ast> {
+    fn visit_expr(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        self.fold_node(expr)
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt<'ast>) -> Result<()> {
+        if let StmtKind::Fn(ident, params, body) = &stmt.kind {
+            return self.fold_fn(ident, params, body);
+        }
+        walk_stmt(self, stmt)?;
+        let span = stmt.span;
+        let folded = match &stmt.kind {
+            StmtKind::Fn(_, _, _) => return Ok(()),
+            StmtKind::EOI => StmtKind::eoi(),
+            StmtKind::Rot(_) => StmtKind::rot(p!(self.pop()?)),
+            StmtKind::Scale(_, _) => {
+                let rhs = self.pop()?;
+                StmtKind::scale(p!(self.pop()?), p!(rhs))
+            }
+            StmtKind::Origin(_, _) => {
+                let rhs = self.pop()?;
+                StmtKind::origin(p!(self.pop()?), p!(rhs))
+            }
+            StmtKind::Canvas(_, _) => {
+                let height = self.pop()?;
+                StmtKind::canvas(p!(self.pop()?), p!(height))
+            }
+            StmtKind::Background(_, _, _) => {
+                let b = self.pop()?;
+                let g = self.pop()?;
+                StmtKind::background(p!(self.pop()?), p!(g), p!(b))
+            }
+            StmtKind::Color(_, _, _) => {
+                let b = self.pop()?;
+                let g = self.pop()?;
+                StmtKind::color(p!(self.pop()?), p!(g), p!(b))
+            }
+            StmtKind::Size(_) => StmtKind::size(p!(self.pop()?)),
+            StmtKind::Style(style) => StmtKind::style(*style),
+            StmtKind::Let(ident, _) => {
+                let value = self.pop()?;
+                if let ExprKind::Ident(ident) = &ident.kind {
+                    let ty = match value.kind {
+                        ExprKind::Lit(_) => IdentTy::Const,
+                        _ => IdentTy::Var,
+                    };
+                    self.environment.bind(ident.name, ty, value);
+                }
+                return Ok(());
+            }
+            StmtKind::Draw(_, _, _, step, _, _) => {
+                let y = self.pop()?;
+                let x = self.pop()?;
+                let step = match step {
+                    Step::Fixed(_) => Step::fixed(p!(self.pop()?)),
+                    Step::Auto => Step::auto(),
+                    Step::Length(_) => Step::length(p!(self.pop()?)),
+                };
+                let to = self.pop()?;
+                let from = self.pop()?;
+                let ident = self.pop()?;
+                let reused = repeats(&[&x, &y]).into_iter().map(|repeat| Change {
+                    kind: ChangeKind::Reused,
+                    span: repeat.span,
+                    result: repeat.to_string(),
+                });
+                self.changes.extend(reused);
+                StmtKind::draw(p!(ident), p!(from), p!(to), step, p!(x), p!(y))
+            }
+        };
+        self.statements.push(Stmt::new(folded, span));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::static_checker::StaticChecker;
+    use crate::parser::{Rule, SrcParser};
+    use pest::Parser;
+
+    /// The folded statements and the changes of `src`, as source.
+    fn fold(src: &str) -> (Vec<String>, Vec<String>) {
+        let pairs = SrcParser::parse(Rule::source, src).unwrap();
+        let source = SrcParser.source(pairs).unwrap();
+        let mut environment = Environment::default();
+        let mut folder = ConstantFolder::new(&mut environment);
+        let statements = folder
+            .fold(&source)
+            .unwrap()
+            .iter()
+            .filter(|stmt| !matches!(stmt.kind, StmtKind::EOI))
+            .map(ToString::to_string)
+            .collect();
+        let changes = folder.changes.iter().map(ToString::to_string).collect();
+        (statements, changes)
+    }
+
+    fn fold_expr(src: &str) -> Result<String> {
+        let pairs = SrcParser::parse(Rule::expression, src).unwrap();
+        let expr = SrcParser.expression(pairs).unwrap();
+        let mut environment = Environment::default();
+        ConstantFolder::new(&mut environment)
+            .fold_expr(&expr)
+            .map(|expr| expr.to_string())
+    }
+
+    #[test]
+    fn folds_literals_into_one_change() {
+        let (statements, changes) = fold("rot is 1 + 2 * 3;");
+        assert_eq!(statements, ["rot is 7;"]);
+        assert_eq!(changes, ["folded `1 + 2 * 3` into `7`"]);
+    }
+
+    #[test]
+    fn substitutes_each_constant() {
+        assert_eq!(fold_expr("PI - PI").unwrap(), "0");
+        assert_eq!(fold_expr("E").unwrap(), "2.7182817");
+        assert_eq!(fold_expr("PI * 2").unwrap(), "6.2831855");
+    }
+
+    #[test]
+    fn keeps_names_it_cannot_evaluate() {
+        let (statements, _) = fold("let k = 3; for T from 0 to 1 step 0.1 draw (Sin(k * T), T);");
+        assert_eq!(
+            statements,
+            ["for T from 0 to 1 step 0.1 draw (Sin(3 * T), T);"]
+        );
+        assert!(matches!(
+            fold_expr("Sin(x)"),
+            Err(Error::Runtime(diagnostic)) if diagnostic.message == "Unresolved ident: x."
+        ));
+    }
+
+    #[test]
+    fn simplifies_identities() {
+        let (statements, changes) =
+            fold("for T from 0 to 1 step 0.1 draw (T * 1 + 0, 1 * T ** 1);");
+        assert_eq!(statements, ["for T from 0 to 1 step 0.1 draw (T, T);"]);
+        assert_eq!(
+            changes,
+            [
+                "simplified `T * 1 + 0` into `T`",
+                "simplified `1 * T ** 1` into `T`",
+            ]
+        );
+        let (statements, _) = fold("for T from 0 to 1 step 0.1 draw (0 * Sin(T), T - 0 / 2);");
+        assert_eq!(statements, ["for T from 0 to 1 step 0.1 draw (0, T);"]);
+    }
+
+    #[test]
+    fn cancels_double_negation() {
+        let (statements, changes) = fold("for T from 0 to 1 step 0.1 draw (- -T, -(-(+T)));");
+        assert_eq!(statements, ["for T from 0 to 1 step 0.1 draw (T, T);"]);
+        assert_eq!(
+            changes,
+            [
+                "simplified `- -T` into `T`",
+                "simplified `-(-(+T))` into `T`",
+            ]
+        );
+        let (statements, _) = fold("for T from 0 to 1 step 0.1 draw (- - -T, T);");
+        assert_eq!(statements, ["for T from 0 to 1 step 0.1 draw (-T, T);"]);
+    }
+
+    #[test]
+    fn reports_reused_subexpressions() {
+        let (statements, changes) =
+            fold("for T from 0 to 1 step 0.1 draw (Cos(T * 2) * Sin(T), Cos(T * 2));");
+        assert_eq!(
+            statements,
+            ["for T from 0 to 1 step 0.1 draw (Cos(T * 2) * Sin(T), Cos(T * 2));"]
+        );
+        assert_eq!(changes, ["reused the value of `Cos(T * 2)`"]);
+        // what is reused is the folded expression
+        let (_, changes) = fold("for T from 0 to 1 step 0.1 draw (Sin(T * 1) + 1, Sin(T) - 1);");
+        assert_eq!(
+            changes,
+            [
+                "simplified `T * 1` into `T`",
+                "reused the value of `Sin(T)`"
+            ]
+        );
+        // a value computed in one branch isn't there in the other
+        let (_, changes) =
+            fold("for T from 0 to 1 step 0.1 draw (if T > 1 then Sin(T) else Sin(T), 0);");
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn lets_params_hide_host_constants() {
+        // a host constant the script doesn't know about, so it passes the checks
+        let src = "fn f(k) = k * 2; for T from 0 to 1 step 0.1 draw (f(T), T);";
+        let pairs = SrcParser::parse(Rule::source, src).unwrap();
+        let source = SrcParser.source(pairs).unwrap();
+        assert!(StaticChecker::default().check(&source).is_empty());
+        let mut environment = Environment::default();
+        environment.set_constant("k", 5.);
+        let statements = ConstantFolder::new(&mut environment).fold(&source).unwrap();
+        assert_eq!(
+            statements[0].to_string(),
+            "for T from 0 to 1 step 0.1 draw (f(T), T);"
+        );
+        assert_eq!(environment.functions["f"].body.to_string(), "k * 2");
+        assert!(matches!(environment.lookup.get("k"), Some(IdentTy::Const)));
+    }
+
+    #[test]
+    fn folds_pure_funcs_and_branches() {
+        let (statements, changes) = fold(
+            "fn half(x) = x / 2; fn wave(x) = if half(4) > 1 then Sin(x) else x;
+             rot is half(PI); for T from 0 to 1 step 0.1 draw (wave(T), T);",
+        );
+        assert_eq!(
+            statements,
+            [
+                "rot is 1.5707964;",
+                "for T from 0 to 1 step 0.1 draw (wave(T), T);"
+            ]
+        );
+        assert_eq!(
+            changes,
+            [
+                "simplified `if half(4) > 1 then Sin(x) else x` into `Sin(x)`",
+                "folded `half(PI)` into `1.5707964`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use crate::ast::{BinOp, Expr, ExprKind, Ident, Lit, Span, UnOp};
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use crate::interpreter::constant_folder::ConstantFolder;
+use crate::interpreter::environment::Environment;
+use crate::p;
+use std::collections::HashMap;
+
+/// The func that gives the derivative of its first argument in the ident
+/// given as the second, `T` when it's left out: `D(Sin(2 * T))` folds into
+/// `Cos(2 * T) * 2`.
+pub const DERIVATIVE: &str = "D";
+
+/// The derivative of `expr` in `var`, folded and simplified by a
+/// [`ConstantFolder`] over `environment`.
+pub fn derive<'ast>(
+    expr: &Expr<'ast>,
+    var: &str,
+    environment: &mut Environment<'ast>,
+) -> Result<Expr<'ast>> {
+    ConstantFolder::new(environment).derive(expr, var)
+}
+
+/// The derivative of the folded `expr` in `var`, before any simplification.
+///
+/// Calls of user funcs are differentiated through their bodies. Comparisons
+/// and logic operators are taken as flat, as is everything that doesn't
+/// mention `var`.
+pub fn differentiate<'ast>(
+    expr: &Expr<'ast>,
+    var: &str,
+    environment: &Environment<'ast>,
+) -> Result<Expr<'ast>> {
+    let d = |expr: &Expr<'ast>| differentiate(expr, var, environment);
+    let at = Build(expr.span);
+    let derivative = match &expr.kind {
+        ExprKind::Lit(_) => at.number(0.),
+        ExprKind::Ident(ident) => at.number(if ident.name == var { 1. } else { 0. }),
+        ExprKind::Grouping(inner) => d(inner)?,
+        ExprKind::Unary(UnOp::Neg, operand) => at.unary(UnOp::Neg, d(operand)?),
+        ExprKind::Unary(UnOp::Pos, operand) => d(operand)?,
+        ExprKind::Unary(UnOp::Not, _) => at.number(0.),
+        ExprKind::If(cond, then, other) => Expr::new(
+            ExprKind::r#if(cond.clone(), p!(d(then)?), p!(d(other)?)),
+            expr.span,
+        ),
+        ExprKind::Binary(u, op, v) => match op {
+            BinOp::Plus | BinOp::Minus => at.binary(d(u)?, *op, d(v)?),
+            BinOp::Asterisk => at.binary(
+                at.binary(d(u)?, BinOp::Asterisk, (**v).clone()),
+                BinOp::Plus,
+                at.binary((**u).clone(), BinOp::Asterisk, d(v)?),
+            ),
+            BinOp::Slash => at.binary(
+                at.binary(
+                    at.binary(d(u)?, BinOp::Asterisk, (**v).clone()),
+                    BinOp::Minus,
+                    at.binary((**u).clone(), BinOp::Asterisk, d(v)?),
+                ),
+                BinOp::Slash,
+                at.binary((**v).clone(), BinOp::Caret, at.number(2.)),
+            ),
+            BinOp::Percent => at.modulo(u, v, d(u)?, d(v)?),
+            BinOp::Caret => at.power(u, v, d(u)?, d(v)?, mentions(v, var, environment)),
+            BinOp::Lt
+            | BinOp::Le
+            | BinOp::Gt
+            | BinOp::Ge
+            | BinOp::Eq
+            | BinOp::Ne
+            | BinOp::And
+            | BinOp::Or => at.number(0.),
+        },
+        ExprKind::Call(callee, args) => {
+            let ExprKind::Ident(ident) = &callee.kind else {
+                return Err(Error::Runtime(Diagnostic::spanned(
+                    "Expect an ident as callee.",
+                    callee.span,
+                    "expect an ident",
+                )));
+            };
+            if let Some(function) = environment.functions.get(ident.name) {
+                let params = function.params.iter().copied().zip(args).collect();
+                return d(&substitute(&function.body, &params));
+            }
+            if !args.iter().any(|arg| mentions(arg, var, environment)) {
+                return Ok(at.number(0.));
+            }
+            let call = |name, args| at.call(name, args);
+            match (ident.name, args.as_slice()) {
+                ("Sin", [u]) => at.chain(call("Cos", vec![u.clone()]), d(u)?),
+                ("Cos", [u]) => at.chain(at.unary(UnOp::Neg, call("Sin", vec![u.clone()])), d(u)?),
+                ("Tan", [u]) => at.binary(
+                    d(u)?,
+                    BinOp::Slash,
+                    at.binary(call("Cos", vec![u.clone()]), BinOp::Caret, at.number(2.)),
+                ),
+                // Exp is 2 to the power of its argument
+                ("Exp", [u]) => at.chain(
+                    at.chain(expr.clone(), call("Ln", vec![at.number(2.)])),
+                    d(u)?,
+                ),
+                ("Sqrt", [u]) => at.binary(
+                    d(u)?,
+                    BinOp::Slash,
+                    at.binary(at.number(2.), BinOp::Asterisk, expr.clone()),
+                ),
+                ("Ln", [u]) => at.binary(d(u)?, BinOp::Slash, u.clone()),
+                ("Pow", [u, v]) => at.power(u, v, d(u)?, d(v)?, mentions(v, var, environment)),
+                ("Mod", [u, v]) => at.modulo(u, v, d(u)?, d(v)?),
+                ("Hypot", [u, v]) => at.binary(
+                    at.binary(
+                        at.chain(u.clone(), d(u)?),
+                        BinOp::Plus,
+                        at.chain(v.clone(), d(v)?),
+                    ),
+                    BinOp::Slash,
+                    expr.clone(),
+                ),
+                ("Atan2", [y, x]) => at.binary(
+                    at.binary(
+                        at.chain(x.clone(), d(y)?),
+                        BinOp::Minus,
+                        at.chain(y.clone(), d(x)?),
+                    ),
+                    BinOp::Slash,
+                    at.binary(
+                        at.binary(x.clone(), BinOp::Caret, at.number(2.)),
+                        BinOp::Plus,
+                        at.binary(y.clone(), BinOp::Caret, at.number(2.)),
+                    ),
+                ),
+                (name, _) => {
+                    return Err(Error::Runtime(Diagnostic::spanned(
+                        format!("Cannot differentiate {}.", name),
+                        expr.span,
+                        format!("{} has no derivative", name),
+                    )))
+                }
+            }
+        }
+    };
+    Ok(derivative)
+}
+
+/// Whether `var` appears in `expr`, or in the body of a user func it calls
+/// where no param hides it.
+fn mentions(expr: &Expr, var: &str, environment: &Environment) -> bool {
+    let m = |expr: &Expr| mentions(expr, var, environment);
+    match &expr.kind {
+        ExprKind::Binary(lhs, _, rhs) => m(lhs) || m(rhs),
+        ExprKind::Unary(_, operand) | ExprKind::Grouping(operand) => m(operand),
+        ExprKind::Call(callee, args) => {
+            let body = match &callee.kind {
+                ExprKind::Ident(ident) => environment
+                    .functions
+                    .get(ident.name)
+                    .is_some_and(|function| !function.params.contains(&var) && m(&function.body)),
+                _ => m(callee),
+            };
+            body || args.iter().any(m)
+        }
+        ExprKind::If(cond, then, other) => m(cond) || m(then) || m(other),
+        ExprKind::Lit(_) => false,
+        ExprKind::Ident(ident) => ident.name == var,
+    }
+}
+
+/// `body` with the idents of `params` replaced by their argument.
+fn substitute<'ast>(body: &Expr<'ast>, params: &HashMap<&str, &Expr<'ast>>) -> Expr<'ast> {
+    let sub = |expr: &Expr<'ast>| p!(substitute(expr, params));
+    let kind = match &body.kind {
+        ExprKind::Binary(lhs, op, rhs) => ExprKind::binary(sub(lhs), *op, sub(rhs)),
+        ExprKind::Unary(op, operand) => ExprKind::unary(*op, sub(operand)),
+        ExprKind::Grouping(inner) => ExprKind::grouping(sub(inner)),
+        ExprKind::Call(callee, args) => ExprKind::call(
+            callee.clone(),
+            args.iter().map(|arg| substitute(arg, params)).collect(),
+        ),
+        ExprKind::If(cond, then, other) => ExprKind::r#if(sub(cond), sub(then), sub(other)),
+        ExprKind::Lit(_) => return body.clone(),
+        ExprKind::Ident(ident) => match params.get(ident.name) {
+            Some(arg) => return (*arg).clone(),
+            None => return body.clone(),
+        },
+    };
+    Expr::new(kind, body.span)
+}
+
+/// Builds the nodes of a derivative, all at the span of what is derived.
+#[derive(Clone, Copy)]
+struct Build<'ast>(Span<'ast>);
+
+impl<'ast> Build<'ast> {
+    fn number(self, value: f32) -> Expr<'ast> {
+        Expr::new(ExprKind::lit(Lit::Number(value)), self.0)
+    }
+
+    fn unary(self, op: UnOp, operand: Expr<'ast>) -> Expr<'ast> {
+        Expr::new(ExprKind::unary(op, p!(operand)), self.0)
+    }
+
+    fn binary(self, lhs: Expr<'ast>, op: BinOp, rhs: Expr<'ast>) -> Expr<'ast> {
+        Expr::new(ExprKind::binary(p!(lhs), op, p!(rhs)), self.0)
+    }
+
+    fn call(self, name: &'ast str, args: Vec<Expr<'ast>>) -> Expr<'ast> {
+        let callee = Expr::new(ExprKind::ident(Ident { name }), self.0);
+        Expr::new(ExprKind::call(p!(callee), args), self.0)
+    }
+
+    /// `outer * inner`, the chain rule.
+    fn chain(self, outer: Expr<'ast>, inner: Expr<'ast>) -> Expr<'ast> {
+        self.binary(outer, BinOp::Asterisk, inner)
+    }
+
+    /// `u % v` is `u - v * k` with `k` whole, so its derivative is
+    /// `u' - v' * k` where `k` is `(u - u % v) / v`.
+    fn modulo(self, u: &Expr<'ast>, v: &Expr<'ast>, du: Expr<'ast>, dv: Expr<'ast>) -> Expr<'ast> {
+        let rem = self.binary(u.clone(), BinOp::Percent, v.clone());
+        let k = self.binary(
+            self.binary(u.clone(), BinOp::Minus, rem),
+            BinOp::Slash,
+            v.clone(),
+        );
+        self.binary(du, BinOp::Minus, self.chain(dv, k))
+    }
+
+    /// `u ** v`, with the power rule when the exponent is flat.
+    fn power(
+        self,
+        u: &Expr<'ast>,
+        v: &Expr<'ast>,
+        du: Expr<'ast>,
+        dv: Expr<'ast>,
+        varying: bool,
+    ) -> Expr<'ast> {
+        if !varying {
+            let lowered = self.binary(v.clone(), BinOp::Minus, self.number(1.));
+            return self.chain(
+                self.chain(v.clone(), self.binary(u.clone(), BinOp::Caret, lowered)),
+                du,
+            );
+        }
+        // u ** v * (v' * Ln(u) + v * u' / u)
+        let ln = self.call("Ln", vec![u.clone()]);
+        let rate = self.binary(
+            self.chain(dv, ln),
+            BinOp::Plus,
+            self.binary(self.chain(v.clone(), du), BinOp::Slash, u.clone()),
+        );
+        self.chain(self.binary(u.clone(), BinOp::Caret, v.clone()), rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+    use crate::interpreter::static_checker::StaticChecker;
+    use crate::parser::{Rule, SrcParser};
+    use pest::Parser;
+
+    /// Whether none of `diagnostics` is an error.
+    fn passes(diagnostics: &[Diagnostic]) -> bool {
+        diagnostics
+            .iter()
+            .all(|diagnostic| !matches!(diagnostic.severity, Severity::Error))
+    }
+
+    /// The folded derivative of `expr` in `T`, once `defs` are defined. Both
+    /// have to pass the static checks.
+    fn derived(defs: &str, expr: &str) -> Result<String> {
+        let pairs = SrcParser::parse(Rule::source, defs).unwrap();
+        let source = SrcParser.source(pairs).unwrap();
+        let pairs = SrcParser::parse(Rule::expression, expr).unwrap();
+        let expr = SrcParser.expression(pairs).unwrap();
+        let mut checker = StaticChecker::default();
+        assert!(passes(&checker.check(&source)), "{}", defs);
+        assert!(passes(&checker.check_expr(&expr).1), "{}", expr);
+        let mut environment = Environment::default();
+        ConstantFolder::new(&mut environment).fold(&source).unwrap();
+        derive(&expr, "T", &mut environment).map(|expr| expr.to_string())
+    }
+
+    fn d(expr: &str) -> String {
+        derived("", expr).unwrap()
+    }
+
+    #[test]
+    fn derives_arithmetic() {
+        assert_eq!(d("3"), "0");
+        assert_eq!(d("T"), "1");
+        assert_eq!(d("-T"), "-1");
+        assert_eq!(d("+T"), "1");
+        assert_eq!(d("2 * T + T"), "3");
+        assert_eq!(d("T - 3 * T"), "-2");
+        assert_eq!(d("T * T"), "T + T");
+        assert_eq!(d("T / (T + 1)"), "(T + 1 - T) / (T + 1) ** 2");
+        assert_eq!(d("1 / T"), "-1 / T ** 2");
+        assert_eq!(d("T % 2"), "1");
+        assert_eq!(d("5 % T"), "0 - (5 - 5 % T) / T");
+    }
+
+    #[test]
+    fn derives_powers() {
+        assert_eq!(d("T ** 3"), "3 * T ** 2");
+        assert_eq!(d("2 ** T"), "2 ** T * 0.6931472");
+        assert_eq!(d("T ** T"), "T ** T * (Ln(T) + T / T)");
+    }
+
+    #[test]
+    fn takes_comparisons_and_logic_as_flat() {
+        assert_eq!(d("T < 1"), "0");
+        assert_eq!(d("not T"), "0");
+        assert_eq!(d("T > 0 and T < 1"), "0");
+        assert_eq!(
+            d("if T > 1 then T * T else 3 * T"),
+            "if T > 1 then T + T else 3"
+        );
+    }
+
+    #[test]
+    fn derives_builtins() {
+        assert_eq!(d("Sin(2 * T)"), "Cos(2 * T) * 2");
+        assert_eq!(d("Cos(T)"), "-Sin(T)");
+        assert_eq!(d("Tan(T)"), "1 / Cos(T) ** 2");
+        assert_eq!(d("Exp(T)"), "Exp(T) * 0.6931472");
+        assert_eq!(d("Sqrt(T)"), "1 / (2 * Sqrt(T))");
+        assert_eq!(d("Ln(T)"), "1 / T");
+        assert_eq!(d("Pow(T, 3)"), "3 * T ** 2");
+        assert_eq!(d("Pow(2, T)"), "2 ** T * 0.6931472");
+        assert_eq!(d("Mod(T, 2)"), "1");
+        assert_eq!(d("Hypot(T, 3)"), "T / Hypot(T, 3)");
+        assert_eq!(d("Atan2(T, 1)"), "1 / (1 + T ** 2)");
+    }
+
+    #[test]
+    fn takes_calls_without_var_as_flat() {
+        assert_eq!(d("Sin(PI)"), "0");
+        assert_eq!(d("Min(1, 2)"), "0");
+        assert!(matches!(
+            derived("", "Min(T, 2)"),
+            Err(Error::Runtime(diagnostic)) if diagnostic.message == "Cannot differentiate Min."
+        ));
+    }
+
+    #[test]
+    fn derives_through_user_funcs() {
+        assert_eq!(
+            derived("fn sq(a) = a * a;", "sq(Sin(T))").unwrap(),
+            "Cos(T) * Sin(T) + Sin(T) * Cos(T)"
+        );
+    }
+
+    #[test]
+    fn lets_a_param_named_like_the_var_hide_it() {
+        // in `g` the var is `a`, which `f` only reads through its param
+        let src = "fn f(a) = a * T; fn g(a) = D(Sin(f(1)) * a, a);";
+        let pairs = SrcParser::parse(Rule::source, src).unwrap();
+        let source = SrcParser.source(pairs).unwrap();
+        assert!(passes(&StaticChecker::default().check(&source)));
+        let mut environment = Environment::default();
+        ConstantFolder::new(&mut environment).fold(&source).unwrap();
+        assert_eq!(environment.functions["g"].body.to_string(), "Sin(f(1))");
+    }
+
+    #[test]
+    fn sees_the_var_read_by_impure_funcs() {
+        let w = "fn w() = 3 * T;";
+        assert_eq!(derived(w, "Sin(w())").unwrap(), "Cos(w()) * 3");
+        assert_eq!(derived(w, "2 ** w()").unwrap(), "2 ** w() * 2.0794415");
+        assert_eq!(derived(w, "Pow(2, w())").unwrap(), "2 ** w() * 2.0794415");
+        assert_eq!(derived(w, "Pow(w(), 2)").unwrap(), "2 * w() * 3");
+    }
+}
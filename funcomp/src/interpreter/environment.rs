@@ -1,4 +1,9 @@
+use crate::ast::{Expr, ExprKind};
+use crate::interpreter::derivative::DERIVATIVE;
+use crate::interpreter::registry::{BuiltinFn, FunctionRegistry};
 use std::collections::HashMap;
+use std::f32::consts;
+use std::fmt::{Display, Formatter};
 
 pub enum IdentTy {
     Var,
@@ -6,8 +11,51 @@ pub enum IdentTy {
     Const,
 }
 
-pub struct Environment {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    /// From the first to the second, both included.
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match self {
+            Arity::Exact(n) => count == *n,
+            Arity::AtLeast(n) => count >= *n,
+            Arity::Between(min, max) => (*min..=*max).contains(&count),
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::Between(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+/// A user-defined `fn`, its body refers to `params` by name.
+pub struct Function<'ast> {
+    pub params: Vec<&'ast str>,
+    pub body: Expr<'ast>,
+    /// Whether the result depends on nothing but the arguments.
+    pub pure: bool,
+}
+
+pub struct Environment<'ast> {
     pub lookup: HashMap<String, IdentTy>,
+    /// Folded right-hand sides of `let` statements, substituted wherever the
+    /// name is used.
+    pub bindings: HashMap<String, Expr<'ast>>,
+    pub functions: HashMap<String, Function<'ast>>,
+    pub registry: FunctionRegistry,
+    /// Values of the named constants, every one is an `IdentTy::Const`.
+    pub constants: HashMap<String, f32>,
 }
 
 macro_rules! lookup_list {
@@ -18,16 +66,95 @@ macro_rules! lookup_list {
     }};
 }
 
-impl Default for Environment {
+impl<'ast> Default for Environment<'ast> {
     fn default() -> Self {
+        Self::new(FunctionRegistry::default())
+    }
+}
+
+impl<'ast> Environment<'ast> {
+    pub fn new(registry: FunctionRegistry) -> Self {
         let mut lookup = HashMap::new();
         lookup.insert("T".into(), IdentTy::Var);
-        lookup_list!(
+        lookup.insert(DERIVATIVE.into(), IdentTy::Func);
+        lookup_list!(lookup, IdentTy::Func, registry.names());
+        let mut environment = Self {
             lookup,
-            IdentTy::Func,
-            ["Sin", "Cos", "Exp", "Sqrt", "Ln", "Tan"]
-        );
-        lookup.insert("PI".into(), IdentTy::Const);
-        Self { lookup }
+            bindings: HashMap::new(),
+            functions: HashMap::new(),
+            registry,
+            constants: HashMap::new(),
+        };
+        environment.set_constant("PI", consts::PI);
+        environment.set_constant("TAU", consts::TAU);
+        environment.set_constant("E", consts::E);
+        environment.set_constant("PHI", 1.618_034);
+        environment
+    }
+
+    /// Adds or replaces the named constant `name`, hosts can use this to
+    /// inject parameters before running a script.
+    pub fn set_constant(&mut self, name: &str, value: f32) {
+        self.lookup.insert(name.into(), IdentTy::Const);
+        self.constants.insert(name.into(), value);
+    }
+
+    /// Makes a host func callable from scripts, see [`FunctionRegistry::register`].
+    pub fn register(&mut self, name: &str, arity: Arity, func: BuiltinFn) {
+        self.lookup.insert(name.into(), IdentTy::Func);
+        self.registry.register(name, arity, func);
+    }
+
+    pub fn bind(&mut self, name: &str, ty: IdentTy, value: Expr<'ast>) {
+        self.lookup.insert(name.into(), ty);
+        self.bindings.insert(name.into(), value);
+    }
+
+    pub fn define(&mut self, name: &str, function: Function<'ast>) {
+        self.lookup.insert(name.into(), IdentTy::Func);
+        self.functions.insert(name.into(), function);
+    }
+
+    /// Whether `expr` reads a Var other than `params`, directly or through an
+    /// impure user function.
+    pub fn depends_on_var(&self, expr: &Expr, params: &[&str]) -> bool {
+        match &expr.kind {
+            ExprKind::Binary(lhs, _, rhs) => {
+                self.depends_on_var(lhs, params) || self.depends_on_var(rhs, params)
+            }
+            ExprKind::Unary(_, operand) => self.depends_on_var(operand, params),
+            ExprKind::Grouping(inner) => self.depends_on_var(inner, params),
+            ExprKind::Call(callee, args) => {
+                let impure = match &callee.kind {
+                    ExprKind::Ident(ident) => self
+                        .functions
+                        .get(ident.name)
+                        .is_some_and(|function| !function.pure),
+                    _ => true,
+                };
+                impure || args.iter().any(|arg| self.depends_on_var(arg, params))
+            }
+            ExprKind::If(cond, then, other) => {
+                self.depends_on_var(cond, params)
+                    || self.depends_on_var(then, params)
+                    || self.depends_on_var(other, params)
+            }
+            ExprKind::Lit(_) => false,
+            ExprKind::Ident(ident) => {
+                !params.contains(&ident.name)
+                    && matches!(self.lookup.get(ident.name), Some(IdentTy::Var))
+            }
+        }
+    }
+
+    /// Number of arguments `name` takes when called, `None` if it isn't a func.
+    pub fn arity(&self, name: &str) -> Option<Arity> {
+        if name == DERIVATIVE {
+            return Some(Arity::Between(1, 2));
+        }
+        match self.functions.get(name) {
+            Some(function) => Some(Arity::Exact(function.params.len())),
+            None => self.registry.get(name).map(|builtin| builtin.arity),
+        }
     }
 }
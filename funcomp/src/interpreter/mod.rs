@@ -1,36 +1,122 @@
-use crate::ast::{BinOp, Expr, Lit, Stmt, UnOp};
-use crate::interpreter::environment::{Environment, IdentTy};
-use crate::interpreter::runtime_solver::RuntimeSolver;
-use crate::interpreter::visit::{walk_expr, walk_stmt, Visitor};
-use crate::p;
-use funcomp_plot::{draw, get_drawing_area, Draw};
-use funcomp_plot::{Circle, EmptyElement, ShapeStyle, RED};
-use std::f32::consts::PI;
-use std::ops::Neg;
+use crate::ast::{Expr, ExprKind, Lit, Step, Stmt, StmtKind};
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use crate::interpreter::bytecode::Program;
+use crate::interpreter::constant_folder::{Change, ConstantFolder};
+use crate::interpreter::environment::Environment;
+use funcomp_plot::{draw, get_drawing_area, get_svg_area, Canvas, Format, Plot, MAX_PIXELS};
+use funcomp_plot::{DrawingBackend, Pen, RGBColor, Style};
+use std::path::PathBuf;
 
+pub mod bytecode;
+pub mod constant_folder;
+pub mod derivative;
 pub mod environment;
+pub mod registry;
 pub mod runtime_solver;
+pub mod sampling;
 pub mod static_checker;
 pub mod visit;
 
+macro_rules! deref_lit {
+    ($lit: expr, $error: literal) => {
+        if let ExprKind::Lit(Lit::Number(lit)) = &$lit.kind {
+            *lit
+        } else {
+            return Err(Error::Runtime(Diagnostic::spanned(
+                $error,
+                $lit.span,
+                "expect a Const",
+            )));
+        }
+    };
+}
+
 #[derive(Clone)]
 pub struct State {
     pub rot: f32,
     pub origin: (f32, f32),
     pub scale: (f32, f32),
+    pub color: RGBColor,
+    /// Dot radius or line width in canvas units.
+    pub size: f32,
+    pub style: Style,
 }
 
 impl Default for State {
     fn default() -> Self {
+        let pen = Pen::default();
         Self {
             rot: 0.,
             origin: (0., 0.),
             scale: (1., 1.),
+            color: pen.color,
+            size: pen.size,
+            style: pen.style,
         }
     }
 }
 
 impl State {
+    /// Applies Scale, then Rot, then Origin to a point.
+    pub fn transform(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let (x, y) = (x * self.scale.0, y * self.scale.1);
+        let rad = self.rot;
+        let (x, y) = (x * rad.cos() + y * rad.sin(), y * rad.cos() - x * rad.sin());
+        (x + self.origin.0, y + self.origin.1)
+    }
+
+    /// Takes on the effect of a folded Rot/Scale/Origin/Color/Size/Style
+    /// statement, anything else is left alone.
+    pub fn apply(&mut self, stmt: &Stmt) -> Result<()> {
+        match &stmt.kind {
+            StmtKind::Rot(expr) => {
+                let lit = deref_lit!(expr, "Expect a Const in Rot");
+                self.rot = lit;
+            }
+            StmtKind::Scale(x, y) => {
+                let x = deref_lit!(x, "Expect a Const in x of Scale");
+                let y = deref_lit!(y, "Expect a Const in y of Scale");
+                self.scale = (x, y);
+            }
+            StmtKind::Origin(x, y) => {
+                let x = deref_lit!(x, "Expect a Const in x of Origin");
+                let y = deref_lit!(y, "Expect a Const in y of Origin");
+                self.origin = (x, y);
+            }
+            StmtKind::Color(r, g, b) => {
+                let r = deref_lit!(r, "Expect a Const in r of Color");
+                let g = deref_lit!(g, "Expect a Const in g of Color");
+                let b = deref_lit!(b, "Expect a Const in b of Color");
+                self.color = RGBColor(channel(r), channel(g), channel(b));
+            }
+            StmtKind::Size(expr) => {
+                let size = deref_lit!(expr, "Expect a Const in Size");
+                if !(size.is_finite() && size > 0.) {
+                    return Err(Error::Runtime(Diagnostic::spanned(
+                        "Size should be a positive number.",
+                        expr.span,
+                        format!("evaluates to {}", size),
+                    )));
+                }
+                self.size = size;
+            }
+            StmtKind::Style(style) => {
+                self.style = *style;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn pen(&self) -> Pen {
+        Pen {
+            color: self.color,
+            size: self.size,
+            style: self.style,
+        }
+    }
+
     pub fn set_scale_x(&mut self, x: f32) {
         self.scale.0 = x;
     }
@@ -48,224 +134,327 @@ impl State {
     }
 }
 
+/// Where and how [`Interpreter::interpret`] renders.
+#[derive(Debug, Clone)]
+pub struct RenderConfig {
+    pub output: PathBuf,
+    pub format: Format,
+    pub canvas: Canvas,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self::new("out.png")
+    }
+}
+
+impl RenderConfig {
+    /// The format follows the extension of `output`, see [`Format::from_path`].
+    pub fn new(output: impl Into<PathBuf>) -> Self {
+        let output = output.into();
+        Self {
+            format: Format::from_path(&output),
+            output,
+            canvas: Canvas::default(),
+        }
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+}
+
 pub struct Interpreter<'ast> {
-    pub environment: Environment,
+    pub environment: Environment<'ast>,
     pub state: State,
-    pub cal_stack: Vec<Expr<'ast>>,
     pub statements: Vec<Stmt<'ast>>,
-    pub draw: Draw<'ast>,
+    /// What folding rewrote so far, see [`ConstantFolder`].
+    pub changes: Vec<Change<'ast>>,
+    /// The canvas part is set up by `canvas is`/`background is` while
+    /// folding, so callers can still adjust it between
+    /// [`Interpreter::accept`] and [`Interpreter::interpret`].
+    pub config: RenderConfig,
 }
 
-impl<'ast> Default for Interpreter<'ast> {
-    fn default() -> Self {
+fn pixels(len: f32, expr: &Expr) -> Result<u32> {
+    if len.is_finite() && len >= 1. && len <= u16::MAX as f32 {
+        Ok(len.round() as u32)
+    } else {
+        Err(Error::Runtime(Diagnostic::spanned(
+            format!("Canvas size should be between 1 and {} pixels.", u16::MAX),
+            expr.span,
+            format!("evaluates to {}", len),
+        )))
+    }
+}
+
+/// Colour channels are clamped into `0..=255`.
+fn channel(value: f32) -> u8 {
+    value.round().clamp(0., 255.) as u8
+}
+
+impl<'ast> Interpreter<'ast> {
+    pub fn new(config: RenderConfig) -> Self {
         Self {
             environment: Environment::default(),
             state: State::default(),
-            cal_stack: vec![],
             statements: vec![],
-            draw: get_drawing_area("out.png"),
+            changes: vec![],
+            config,
         }
     }
-}
 
-macro_rules! deref_lit {
-    ($lit: expr, $error: literal) => {
-        if let Expr::Lit(Lit::Number(lit)) = &**$lit {
-            *lit
-        } else {
-            panic!($error)
+    /// Uses `environment` instead of the default one, e.g. to make host funcs
+    /// from a [`registry::FunctionRegistry`] available to the script.
+    pub fn with_environment(mut self, environment: Environment<'ast>) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    pub fn accept(mut self, src: &[Stmt<'ast>]) -> Result<Self> {
+        self.fold(src)?;
+        Ok(self)
+    }
+
+    /// Folds `src` with a [`ConstantFolder`] on top of what was folded before
+    /// and returns the statements it added; `let` and `fn` only add to the
+    /// environment.
+    pub fn fold(&mut self, src: &[Stmt<'ast>]) -> Result<&[Stmt<'ast>]> {
+        let start = self.statements.len();
+        let mut folder = ConstantFolder::new(&mut self.environment);
+        let folded = folder.fold(src)?;
+        self.changes.append(&mut folder.changes);
+        for stmt in folded {
+            self.configure(&stmt)?;
+            self.statements.push(stmt);
         }
-    };
-}
+        Ok(&self.statements[start..])
+    }
 
-impl<'ast> Interpreter<'ast> {
-    pub fn accept(mut self, src: &[Stmt<'ast>]) -> Self {
-        for stmt in src.iter() {
-            self.visit_stmt(stmt);
+    pub fn fold_expr(&mut self, expr: &Expr<'ast>) -> Result<Expr<'ast>> {
+        ConstantFolder::new(&mut self.environment).fold_expr(expr)
+    }
+
+    pub fn interpret(mut self) -> Result<Self> {
+        self.render_output()?;
+        Ok(self)
+    }
+
+    /// Draws everything folded so far into the configured output, replaying
+    /// the statements from the default [`State`].
+    pub fn render_output(&mut self) -> Result<()> {
+        if !self.config.canvas.is_drawable() {
+            return Err(Error::Render(format!(
+                "canvas of {}x{} has no room left inside its {} margin.",
+                self.config.canvas.size.0, self.config.canvas.size.1, self.config.canvas.margin
+            )));
         }
-        for i in self.statements.iter() {
-            println!("{:?}", i)
+        if !self.config.canvas.fits() {
+            let (width, height) = self.config.canvas.pixel_size();
+            return Err(Error::Render(format!(
+                "canvas of {}x{} pixels is larger than the {} pixels allowed.",
+                width, height, MAX_PIXELS
+            )));
+        }
+        self.state = State::default();
+        let output = self.config.output.clone();
+        match self.config.format {
+            Format::Png => self.render(&get_drawing_area(&output, &self.config.canvas)?),
+            Format::Svg => self.render(&get_svg_area(&output, &self.config.canvas)?),
         }
-        self
     }
 
-    pub fn interpret(mut self) -> Self {
+    /// Runs the folded statements against `draw`, whatever its backend is.
+    fn render<DB: DrawingBackend>(&mut self, draw: &Plot<DB>) -> Result<()> {
         for stmt in self.statements.iter() {
-            match stmt {
-                Stmt::Draw(_, from, to, step, x, y) => {
-                    let mut from = deref_lit!(from, "Expect a Const in from of Draw");
-                    let mut to = deref_lit!(to, "Expect a Const in to of Draw");
-                    let step = deref_lit!(step, "Expect a Const in step of Draw");
+            match &stmt.kind {
+                StmtKind::Draw(ident, from_expr, to_expr, step, x, y) => {
+                    let mut from = deref_lit!(from_expr, "Expect a Const in from of Draw");
+                    let mut to = deref_lit!(to_expr, "Expect a Const in to of Draw");
+                    for (value, expr) in [(from, from_expr), (to, to_expr)] {
+                        if !value.is_finite() {
+                            return Err(Error::Runtime(Diagnostic::spanned(
+                                "Range of Draw should be finite.",
+                                expr.span,
+                                format!("evaluates to {}", value),
+                            )));
+                        }
+                    }
 
                     if from > to {
                         std::mem::swap(&mut from, &mut to);
                     }
 
-                    let diff = to - from;
-                    if diff < step {
-                        panic!("Step should smaller than diff between from and to.")
-                    }
+                    // first transform: from func to dots
+                    let program = Program::compile(&[x, y], &self.environment)?;
+                    let xys = match step {
+                        Step::Fixed(step) => {
+                            let step_span = step.span;
+                            let step = deref_lit!(step, "Expect a Const in step of Draw");
+                            if !(step.is_finite() && step > 0.) {
+                                return Err(Error::Runtime(Diagnostic::spanned(
+                                    "Step should be a positive number.",
+                                    step_span,
+                                    format!("evaluates to {}", step),
+                                )));
+                            }
+                            let diff = to - from;
+                            if diff / step > sampling::MAX_FIXED_SAMPLES as f32 {
+                                return Err(Error::Runtime(Diagnostic::spanned(
+                                    format!(
+                                        "Step leaves more than {} samples.",
+                                        sampling::MAX_FIXED_SAMPLES
+                                    ),
+                                    step_span,
+                                    "step too small",
+                                )));
+                            }
+                            if diff < step {
+                                return Err(Error::Runtime(Diagnostic::spanned(
+                                    "Step should smaller than diff between from and to.",
+                                    step_span,
+                                    "step too large",
+                                )));
+                            }
 
-                    let mut range = vec![];
-                    for i in 0.. {
-                        if from + (i as f32 * step) > to {
-                            range.push(to);
-                            break;
-                        } else {
-                            range.push(from + (i as f32 * step));
+                            let mut range = vec![];
+                            for i in 0.. {
+                                if from + (i as f32 * step) > to {
+                                    range.push(to);
+                                    break;
+                                } else {
+                                    range.push(from + (i as f32 * step));
+                                }
+                            }
+                            program.eval_points(&range)
                         }
-                    }
-                    let mut solver = RuntimeSolver::new(range);
-                    let xs = solver.solve_all(x);
-                    let ys = solver.solve_all(y);
-                    // first transform: from func to dots
-                    let xys: Vec<(f32, f32)> = xs.into_iter().zip(ys.into_iter()).collect();
+                        Step::Length(step) => {
+                            let step_span = step.span;
+                            let step = deref_lit!(step, "Expect a Const in step of Draw");
+                            if !(step.is_finite() && step > 0.) {
+                                return Err(Error::Runtime(Diagnostic::spanned(
+                                    "Step by length should be a positive number.",
+                                    step_span,
+                                    format!("evaluates to {}", step),
+                                )));
+                            }
+                            let ts: Vec<f32> = (0..=sampling::LENGTH_SEGMENTS)
+                                .map(|i| {
+                                    from + (to - from) * i as f32 / sampling::LENGTH_SEGMENTS as f32
+                                })
+                                .collect();
+                            let var = match &ident.kind {
+                                ExprKind::Ident(ident) => ident.name,
+                                _ => "T",
+                            };
+                            let state = &self.state;
+                            let lengths = sampling::arc_lengths(
+                                &ts,
+                                (x, y),
+                                var,
+                                &self.environment,
+                                &program,
+                                |xy| state.transform(xy),
+                            )?;
+                            let total = lengths.last().copied().unwrap_or_default();
+                            if total / step > sampling::MAX_LENGTH_SAMPLES as f32 {
+                                return Err(Error::Runtime(Diagnostic::spanned(
+                                    format!(
+                                        "Step by length leaves more than {} samples.",
+                                        sampling::MAX_LENGTH_SAMPLES
+                                    ),
+                                    step_span,
+                                    format!("the curve is {} long", total),
+                                )));
+                            }
+                            program.eval_points(&sampling::by_length(&ts, &lengths, step))
+                        }
+                        Step::Auto => {
+                            let dpi_scale = self.config.canvas.dpi_scale;
+                            let state = &self.state;
+                            sampling::adaptive(&program, (from, to), |xy| {
+                                let (x, y) = state.transform(xy);
+                                (x * dpi_scale, y * dpi_scale)
+                            })
+                        }
+                    };
 
                     // second transform: apply the effect of Rot/Scale/Origin
-                    let xys = xys
-                        .into_iter()
-                        .map(|(x, y)| (x * self.state.scale.0, y * self.state.scale.1))
-                        .map(|(x, y)| {
-                            let rad = self.state.rot;
-                            (x * rad.cos() + y * rad.sin(), y * rad.cos() - x * rad.sin())
-                        })
-                        .map(|(x, y)| (x + self.state.origin.0, y + self.state.origin.1));
-                    draw!(self.draw, xys);
-                }
-                Stmt::Rot(expr) => {
-                    let lit = deref_lit!(expr, "Expect a Const in Rot");
-                    self.state.rot = lit;
-                }
-                Stmt::Scale(x, y) => {
-                    let x = deref_lit!(x, "Expect a Const in x of Scale");
-                    let y = deref_lit!(y, "Expect a Const in y of Scale");
-                    self.state.scale = (x, y);
-                }
-                Stmt::Origin(x, y) => {
-                    let x = deref_lit!(x, "Expect a Const in x of Origin");
-                    let y = deref_lit!(y, "Expect a Const in y of Origin");
-                    self.state.origin = (x, y);
-                }
-                Stmt::EOI => {
-                    self.draw.present().unwrap();
+                    let xys = xys.into_iter().map(|xy| self.state.transform(xy));
+                    let pen = self.config.canvas.scale_pen(&self.state.pen());
+                    draw!(draw, xys, &pen)?;
                 }
+                StmtKind::EOI => {}
+                _ => self.state.apply(stmt)?,
             }
         }
-        self
+        draw.present()?;
+        Ok(())
     }
-}
 
-impl<'ast> Visitor<'ast> for Interpreter<'ast> {
-    fn visit_expr(&mut self, expr: &Expr<'ast>) {
-        walk_expr(self, expr);
-        match expr {
-            Expr::Binary(_, op, _) => {
-                let rhs = self.cal_stack.pop().unwrap();
-                let lhs = self.cal_stack.pop().unwrap();
-                match (lhs, rhs) {
-                    (Expr::Lit(Lit::Number(lhs)), Expr::Lit(Lit::Number(rhs))) => {
-                        let operation = match op {
-                            BinOp::Plus => |l, r| l + r,
-                            BinOp::Minus => |l, r| l - r,
-                            BinOp::Asterisk => |l, r| l * r,
-                            BinOp::Slash => |l, r| l / r,
-                        };
-                        let result = operation(lhs, rhs);
-                        self.cal_stack.push(Expr::Lit(Lit::Number(result)))
-                    }
-                    (lhs, rhs) => {
-                        self.cal_stack
-                            .push(Expr::binary(p!(lhs), op.clone(), p!(rhs)));
-                    }
-                }
+    /// Sets up the canvas from a folded `canvas is`/`background is`.
+    fn configure(&mut self, stmt: &Stmt<'ast>) -> Result<()> {
+        match &stmt.kind {
+            StmtKind::Canvas(width, height) => {
+                self.config.canvas.size = (
+                    pixels(
+                        deref_lit!(width, "Expect a Const in width of Canvas"),
+                        width,
+                    )?,
+                    pixels(
+                        deref_lit!(height, "Expect a Const in height of Canvas"),
+                        height,
+                    )?,
+                );
             }
-            Expr::Unary(op, _) => {
-                let operand = self.cal_stack.pop().unwrap();
-                match operand {
-                    Expr::Lit(Lit::Number(lit)) => match op {
-                        UnOp::Neg => self.cal_stack.push(Expr::lit(Lit::Number(lit.neg()))),
-                        UnOp::Pos => self.cal_stack.push(Expr::lit(Lit::Number(lit))),
-                    },
-                    _ => self.cal_stack.push(Expr::unary(op.clone(), p!(operand))),
-                }
-            }
-            Expr::Call(_, _) => {
-                let arg = self.cal_stack.pop().unwrap();
-                let callee = self.cal_stack.pop().unwrap();
-                match arg {
-                    Expr::Lit(lit) => {
-                        let Lit::Number(lit) = lit;
-                        if let Expr::Ident(callee) = callee {
-                            let name = callee.name;
-                            let result = match name {
-                                "Sin" => f32::sin(lit),
-                                "Cos" => f32::cos(lit),
-                                "Tan" => f32::tan(lit),
-                                "Sqrt" => f32::sqrt(lit),
-                                "Exp" => f32::exp2(lit),
-                                "Ln" => f32::ln(lit),
-                                _ => panic!("Invalid internal func."),
-                            };
-                            self.cal_stack.push(Expr::lit(Lit::Number(result)))
-                        } else {
-                            panic!("Can't reach")
-                        }
-                    }
-                    _ => self.cal_stack.push(Expr::call(p!(callee), vec![arg])),
-                }
-            }
-            Expr::Grouping(_) => {
-                let inner = self.cal_stack.pop().unwrap();
-                self.cal_stack.push(inner);
-            }
-            Expr::Lit(lit) => self.cal_stack.push(Expr::lit(lit.clone())),
-            Expr::Ident(ident) => {
-                let name = ident.name;
-                let ty = self.environment.lookup.get(name).unwrap();
-                match ty {
-                    IdentTy::Const => self.cal_stack.push(Expr::lit(Lit::Number(PI))),
-                    _ => self.cal_stack.push(Expr::ident(ident.clone())),
-                }
+            StmtKind::Background(r, g, b) => {
+                self.config.canvas.background = RGBColor(
+                    channel(deref_lit!(r, "Expect a Const in r of Background")),
+                    channel(deref_lit!(g, "Expect a Const in g of Background")),
+                    channel(deref_lit!(b, "Expect a Const in b of Background")),
+                );
             }
+            _ => {}
         }
+        Ok(())
     }
+}
 
-    fn visit_stmt(&mut self, stmt: &Stmt<'ast>) {
-        walk_stmt(self, stmt);
-        match stmt {
-            Stmt::EOI => {
-                self.statements.push(Stmt::eoi());
-            }
-            Stmt::Rot(_) => {
-                let inner = self.cal_stack.pop().unwrap();
-                self.statements.push(Stmt::rot(p!(inner)));
-            }
-            Stmt::Scale(_, _) => {
-                let rhs = self.cal_stack.pop().unwrap();
-                let lhs = self.cal_stack.pop().unwrap();
-                self.statements.push(Stmt::scale(p!(lhs), p!(rhs)));
-            }
-            Stmt::Origin(_, _) => {
-                let rhs = self.cal_stack.pop().unwrap();
-                let lhs = self.cal_stack.pop().unwrap();
-                self.statements.push(Stmt::origin(p!(lhs), p!(rhs)));
-            }
-            Stmt::Draw(_, _, _, _, _, _) => {
-                let y = self.cal_stack.pop().unwrap();
-                let x = self.cal_stack.pop().unwrap();
-                let step = self.cal_stack.pop().unwrap();
-                let to = self.cal_stack.pop().unwrap();
-                let from = self.cal_stack.pop().unwrap();
-                let ident = self.cal_stack.pop().unwrap();
-                self.statements.push(Stmt::draw(
-                    p!(ident),
-                    p!(from),
-                    p!(to),
-                    p!(step),
-                    p!(x),
-                    p!(y),
-                ));
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Rule, SrcParser};
+    use funcomp_plot::{plot_area, SVGBackend};
+    use pest::Parser;
+
+    /// Renders `src` into an SVG that is thrown away.
+    fn render(src: &str) -> Result<()> {
+        let source = SrcParser.source(SrcParser::parse(Rule::source, src)?)?;
+        let mut interpreter = Interpreter::new(RenderConfig::default()).accept(&source)?;
+        let canvas = interpreter.config.canvas;
+        let mut out = String::new();
+        let backend = SVGBackend::with_string(&mut out, canvas.pixel_size());
+        let plot = plot_area(backend, &canvas)?;
+        interpreter.render(&plot)
+    }
+
+    #[test]
+    fn rejects_a_range_that_is_not_finite() {
+        for src in [
+            "for T from Ln(0 - 1) to 1 step 0.1 draw (T, T);",
+            "for T from 0 to 1 / 0 step 0.1 draw (T, T);",
+            "for T from Ln(0 - 1) to 1 step auto draw (T, T);",
+        ] {
+            assert!(
+                matches!(
+                    render(src),
+                    Err(Error::Runtime(diagnostic)) if diagnostic.message == "Range of Draw should be finite."
+                ),
+                "{}",
+                src
+            );
         }
+        assert!(render("for T from 0 to 1 step 0.1 draw (T, T);").is_ok());
     }
 }
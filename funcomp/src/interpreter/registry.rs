@@ -0,0 +1,80 @@
+use crate::interpreter::environment::Arity;
+use std::collections::HashMap;
+
+pub type BuiltinFn = fn(&[f32]) -> f32;
+
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub arity: Arity,
+    pub func: BuiltinFn,
+}
+
+/// Built-in funcs known to the checker, the constant folder and the runtime
+/// solver. Hosts can `register` their own before running a script, the
+/// default registry holds the standard set.
+#[derive(Clone)]
+pub struct FunctionRegistry {
+    pub functions: HashMap<String, Builtin>,
+}
+
+impl FunctionRegistry {
+    pub fn empty() -> Self {
+        Self {
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Adds or replaces `name`. `func` is only called with an argument count
+    /// `arity` accepts.
+    pub fn register(&mut self, name: &str, arity: Arity, func: BuiltinFn) -> &mut Self {
+        self.functions.insert(name.into(), Builtin { arity, func });
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Builtin> {
+        self.functions.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.functions.keys().map(|name| name.as_str())
+    }
+
+    /// Applies the built-in func `name`, `None` if there is no such func or it
+    /// doesn't accept this many arguments.
+    pub fn call(&self, name: &str, args: &[f32]) -> Option<f32> {
+        let builtin = self.get(name)?;
+        if !builtin.arity.accepts(args.len()) {
+            return None;
+        }
+        Some((builtin.func)(args))
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .register("Sin", Arity::Exact(1), |args| args[0].sin())
+            .register("Cos", Arity::Exact(1), |args| args[0].cos())
+            .register("Tan", Arity::Exact(1), |args| args[0].tan())
+            .register("Exp", Arity::Exact(1), |args| args[0].exp2())
+            .register("Sqrt", Arity::Exact(1), |args| args[0].sqrt())
+            .register("Ln", Arity::Exact(1), |args| args[0].ln())
+            .register("Atan2", Arity::Exact(2), |args| args[0].atan2(args[1]))
+            .register("Pow", Arity::Exact(2), |args| args[0].powf(args[1]))
+            .register("Hypot", Arity::Exact(2), |args| args[0].hypot(args[1]))
+            // the result is never negative, so Mod(-1, 3) is 2 and Mod(1, -3) is 1
+            .register("Mod", Arity::Exact(2), |args| args[0].rem_euclid(args[1]))
+            // f32::clamp panics on lo > hi, a script shouldn't be able to do that
+            .register("Clamp", Arity::Exact(3), |args| {
+                args[0].max(args[1]).min(args[2])
+            })
+            .register("Min", Arity::AtLeast(1), |args| {
+                args[1..].iter().fold(args[0], |acc, x| acc.min(*x))
+            })
+            .register("Max", Arity::AtLeast(1), |args| {
+                args[1..].iter().fold(args[0], |acc, x| acc.max(*x))
+            });
+        registry
+    }
+}
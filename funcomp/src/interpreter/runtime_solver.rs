@@ -1,95 +1,150 @@
-use crate::ast::{BinOp, Expr, Lit, UnOp};
+use crate::ast::{Expr, ExprKind, Lit, Span};
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
 use crate::interpreter::environment::{Environment, IdentTy};
 use crate::interpreter::visit::{walk_expr, Visitor};
+use std::collections::HashMap;
 
-pub struct RuntimeSolver {
+/// Deepest chain of user function calls before evaluation gives up.
+pub const MAX_CALL_DEPTH: usize = 64;
+
+pub struct RuntimeSolver<'env, 'ast> {
     pub t: Vec<f32>,
     pub stack: Vec<f32>,
-    pub environment: Environment,
+    pub environment: &'env Environment<'ast>,
+    /// Argument bindings of the user functions currently being evaluated.
+    pub frames: Vec<HashMap<&'ast str, f32>>,
     pub cur_t: f32,
 }
 
-impl RuntimeSolver {
-    pub fn new(t: Vec<f32>) -> Self {
+impl<'env, 'ast> RuntimeSolver<'env, 'ast> {
+    pub fn new(t: Vec<f32>, environment: &'env Environment<'ast>) -> Self {
         Self {
             t,
             stack: vec![],
-            environment: Environment::default(),
+            environment,
+            frames: vec![],
             cur_t: 0.,
         }
     }
 
-    pub fn solve_all(&mut self, expr: &Expr) -> Vec<f32> {
+    pub fn solve_all(&mut self, expr: &Expr<'ast>) -> Result<Vec<f32>> {
         self.t
             .clone()
             .iter()
             .map(|t| {
                 self.cur_t = *t;
-                self.visit_expr(expr);
-                self.stack.pop().unwrap()
+                self.visit_expr(expr)?;
+                self.pop()
             })
             .collect()
     }
+
+    /// Evaluates the user function `name` with `args` bound to its params.
+    pub fn call(&mut self, name: &str, args: Vec<f32>, span: Span) -> Result<f32> {
+        let environment = self.environment;
+        let function = environment.functions.get(name).ok_or_else(|| {
+            Error::Runtime(Diagnostic::spanned(
+                format!("{} is not a user func.", name),
+                span,
+                "unknown func",
+            ))
+        })?;
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(Error::Runtime(Diagnostic::spanned(
+                "Call depth exceeded.",
+                span,
+                "too deeply nested",
+            )));
+        }
+        self.frames
+            .push(function.params.iter().copied().zip(args).collect());
+        let result = self.visit_expr(&function.body).and_then(|_| self.pop());
+        self.frames.pop();
+        result
+    }
+
+    fn pop(&mut self) -> Result<f32> {
+        self.stack
+            .pop()
+            .ok_or_else(|| Error::Runtime(Diagnostic::new("Value stack underflow.")))
+    }
 }
 
-impl<'ast> Visitor<'ast> for RuntimeSolver {
-    fn visit_expr(&mut self, expr: &Expr<'ast>) {
-        walk_expr(self, expr);
-        match expr {
-            Expr::Binary(_, op, _) => {
-                let rhs = self.stack.pop().unwrap();
-                let lhs = self.stack.pop().unwrap();
-                let result = match op {
-                    BinOp::Plus => lhs + rhs,
-                    BinOp::Minus => lhs - rhs,
-                    BinOp::Asterisk => lhs * rhs,
-                    BinOp::Slash => lhs / rhs,
-                };
-                self.stack.push(result);
+impl<'env, 'ast> Visitor<'ast> for RuntimeSolver<'env, 'ast> {
+    fn visit_expr(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        if let ExprKind::If(cond, then, other) = &expr.kind {
+            // only the taken branch is evaluated
+            self.visit_expr(cond)?;
+            return if self.pop()? != 0. {
+                self.visit_expr(then)
+            } else {
+                self.visit_expr(other)
+            };
+        }
+        walk_expr(self, expr)?;
+        match &expr.kind {
+            ExprKind::Binary(_, op, _) => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                self.stack.push(op.apply(lhs, rhs));
             }
-            Expr::Unary(op, _) => {
-                let operand = self.stack.pop().unwrap();
-                match op {
-                    UnOp::Neg => self.stack.push(-operand),
-                    UnOp::Pos => self.stack.push(operand),
-                }
+            ExprKind::Unary(op, _) => {
+                let operand = self.pop()?;
+                self.stack.push(op.apply(operand));
             }
-            Expr::Call(callee, _) => {
-                let arg = self.stack.pop().unwrap();
-                if let Expr::Ident(callee) = &**callee {
-                    let name = callee.name;
-                    if let Some(lookup) = self.environment.lookup.get(name) {
-                        match lookup {
-                            IdentTy::Func => match name {
-                                "Sin" => self.stack.push(f32::sin(arg)),
-                                "Cos" => self.stack.push(f32::cos(arg)),
-                                "Tan" => self.stack.push(f32::tan(arg)),
-                                "Exp" => self.stack.push(f32::exp2(arg)),
-                                "Sqrt" => self.stack.push(f32::sqrt(arg)),
-                                "Ln" => self.stack.push(f32::ln(arg)),
-                                _ => panic!("Invalid func name during runtime."),
-                            },
-                            IdentTy::Var => {}
-                            IdentTy::Const => {}
-                        }
-                    }
-                }
+            ExprKind::Call(callee, args) => {
+                let ExprKind::Ident(ident) = &callee.kind else {
+                    return Err(Error::Runtime(Diagnostic::spanned(
+                        "Expect an ident as callee.",
+                        callee.span,
+                        "expect an ident",
+                    )));
+                };
+                let name = ident.name;
+                let at = self.stack.len().saturating_sub(args.len());
+                let args = self.stack.split_off(at);
+                let result = if self.environment.functions.contains_key(name) {
+                    self.call(name, args, expr.span)?
+                } else {
+                    self.environment.registry.call(name, &args).ok_or_else(|| {
+                        Error::Runtime(Diagnostic::spanned(
+                            "Invalid func name during runtime.",
+                            callee.span,
+                            "unknown func",
+                        ))
+                    })?
+                };
+                self.stack.push(result);
             }
-            Expr::Lit(lit) => {
+            ExprKind::Lit(lit) => {
                 let Lit::Number(lit) = *lit;
                 self.stack.push(lit);
             }
-            Expr::Ident(ident) => {
+            ExprKind::Ident(ident) => {
                 let name = ident.name;
-                if let Some(lookup) = self.environment.lookup.get(name) {
-                    match lookup {
-                        IdentTy::Var => self.stack.push(self.cur_t),
-                        IdentTy::Func => {}
-                        IdentTy::Const => {}
+                if let Some(value) = self.frames.last().and_then(|frame| frame.get(name)) {
+                    self.stack.push(*value);
+                    return Ok(());
+                }
+                if let Some(value) = self.environment.constants.get(name) {
+                    self.stack.push(*value);
+                    return Ok(());
+                }
+                match self.environment.lookup.get(name) {
+                    Some(IdentTy::Var) => self.stack.push(self.cur_t),
+                    Some(IdentTy::Func) => {}
+                    Some(IdentTy::Const) | None => {
+                        return Err(Error::Runtime(Diagnostic::spanned(
+                            format!("Unresolved ident: {}.", name),
+                            expr.span,
+                            "unresolved at runtime",
+                        )))
                     }
                 }
             }
-            Expr::Grouping(_) => {}
+            ExprKind::Grouping(_) | ExprKind::If(_, _, _) => {}
         }
+        Ok(())
     }
 }
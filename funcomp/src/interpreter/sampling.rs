@@ -0,0 +1,275 @@
+use crate::ast::Expr;
+use crate::error::Result;
+use crate::interpreter::bytecode::Program;
+use crate::interpreter::derivative::differentiate;
+use crate::interpreter::environment::Environment;
+use crate::interpreter::runtime_solver::RuntimeSolver;
+
+/// How far, in device pixels, the curve may stray from the straight segment
+/// between two neighbouring samples before `step auto` splits it.
+pub const TOLERANCE: f32 = 0.5;
+
+/// Upper bound on the samples of a single `step auto` draw.
+pub const MAX_SAMPLES: usize = 10_000;
+
+/// Upper bound on the samples of a single draw with a fixed step.
+pub const MAX_FIXED_SAMPLES: usize = 1_000_000;
+
+/// Fraction of the range below which a segment that is still too rough is
+/// taken to be a jump, such as the pole of `1 / T`, and is left open.
+const MIN_SEGMENT: f32 = 1e-5;
+
+/// Segments the range is cut into before refining, so that a feature which
+/// happens to be symmetric around the middle of the range isn't missed.
+const INITIAL_SEGMENTS: usize = 32;
+
+/// Intervals the range is cut into to measure the length of the curve for
+/// `step by length`.
+pub const LENGTH_SEGMENTS: usize = 4096;
+
+/// Upper bound on the samples of a single `step by length` draw.
+pub const MAX_LENGTH_SAMPLES: usize = 1_000_000;
+
+/// Samples the `(x, y)` `program` over `from..=to`, halving every segment
+/// whose midpoint lands more than [`TOLERANCE`] away from the segment once
+/// `project`ed into device pixels, until no segment needs it or
+/// [`MAX_SAMPLES`] is hit.
+pub fn adaptive(
+    program: &Program,
+    (from, to): (f32, f32),
+    project: impl Fn((f32, f32)) -> (f32, f32),
+) -> Vec<(f32, f32)> {
+    let mut ts: Vec<f32> = (0..=INITIAL_SEGMENTS)
+        .map(|i| from + (to - from) * i as f32 / INITIAL_SEGMENTS as f32)
+        .collect();
+    let mut points = program.eval_points(&ts);
+    // whether the segment starting at the same index may still be split
+    let mut open = vec![true; INITIAL_SEGMENTS];
+
+    loop {
+        let budget = MAX_SAMPLES.saturating_sub(ts.len());
+        let splits: Vec<usize> = (0..open.len()).filter(|i| open[*i]).take(budget).collect();
+        if splits.is_empty() {
+            break;
+        }
+        let mids: Vec<f32> = splits.iter().map(|i| (ts[*i] + ts[i + 1]) / 2.).collect();
+        let mid_points = program.eval_points(&mids);
+
+        let mut next_ts = Vec::with_capacity(ts.len() + splits.len());
+        let mut next_points = Vec::with_capacity(ts.len() + splits.len());
+        let mut next_open = Vec::with_capacity(open.len() + splits.len());
+        let mut split = splits.iter().zip(mids).zip(mid_points).peekable();
+        for i in 0..open.len() {
+            next_ts.push(ts[i]);
+            next_points.push(points[i]);
+            let Some(((_, t), point)) = split.next_if(|((j, _), _)| **j == i) else {
+                // still open if it only missed out on the budget
+                next_open.push(open[i]);
+                continue;
+            };
+            if !is_rough(points[i], point, points[i + 1], &project) {
+                next_open.push(false);
+                continue;
+            }
+            if ts[i + 1] - ts[i] < (to - from) * MIN_SEGMENT || t <= ts[i] || t >= ts[i + 1] {
+                // a non-finite sample breaks the path there
+                next_ts.push(t);
+                next_points.push((f32::NAN, f32::NAN));
+                next_open.extend([false, false]);
+                continue;
+            }
+            next_ts.push(t);
+            next_points.push(point);
+            next_open.extend([true, true]);
+        }
+        next_ts.extend(ts.last());
+        next_points.extend(points.last());
+
+        ts = next_ts;
+        points = next_points;
+        open = next_open;
+    }
+    points
+}
+
+/// Whether `mid` is too far off the segment from `start` to `end`. Segments
+/// with a non-finite end are split further to narrow the gap down.
+fn is_rough(
+    start: (f32, f32),
+    mid: (f32, f32),
+    end: (f32, f32),
+    project: impl Fn((f32, f32)) -> (f32, f32),
+) -> bool {
+    let finite = [start, mid, end]
+        .iter()
+        .filter(|(x, y)| x.is_finite() && y.is_finite())
+        .count();
+    match finite {
+        0 => false,
+        3 => {
+            let (start, mid, end) = (project(start), project(mid), project(end));
+            let chord = ((start.0 + end.0) / 2., (start.1 + end.1) / 2.);
+            (mid.0 - chord.0).hypot(mid.1 - chord.1) > TOLERANCE
+        }
+        _ => true,
+    }
+}
+
+/// Distance along the `(x, y)` curve from `ts[0]` to each of `ts`, once
+/// `project`ed onto the canvas.
+///
+/// The speed of the curve comes from the derivatives of `x` and `y` in `var`,
+/// evaluated by a [`RuntimeSolver`]. When one of them has no derivative, as
+/// with `Min` or a host func, the chords between the samples of `program`
+/// stand in for the curve instead. Stretches that aren't finite don't count.
+pub fn arc_lengths<'ast>(
+    ts: &[f32],
+    (x, y): (&Expr<'ast>, &Expr<'ast>),
+    var: &str,
+    environment: &Environment<'ast>,
+    program: &Program,
+    project: impl Fn((f32, f32)) -> (f32, f32),
+) -> Result<Vec<f32>> {
+    let distance = |(x0, y0): (f32, f32), (x1, y1): (f32, f32)| {
+        let d = (x1 - x0).hypot(y1 - y0);
+        if d.is_finite() {
+            d
+        } else {
+            0.
+        }
+    };
+    let derivatives = differentiate(x, var, environment)
+        .and_then(|dx| Ok((dx, differentiate(y, var, environment)?)));
+    let steps: Vec<f32> = match derivatives {
+        Ok((dx, dy)) => {
+            let dxs = RuntimeSolver::new(ts.to_vec(), environment).solve_all(&dx)?;
+            let dys = RuntimeSolver::new(ts.to_vec(), environment).solve_all(&dy)?;
+            // the transform is affine, its linear part carries velocities over
+            let origin = project((0., 0.));
+            let speeds: Vec<f32> = dxs
+                .into_iter()
+                .zip(dys)
+                .map(|velocity| distance(origin, project(velocity)))
+                .collect();
+            // trapezoids between neighbouring samples
+            ts.windows(2)
+                .zip(speeds.windows(2))
+                .map(|(t, speed)| (t[1] - t[0]) * (speed[0] + speed[1]) / 2.)
+                .collect()
+        }
+        Err(_) => {
+            let points: Vec<(f32, f32)> =
+                program.eval_points(ts).into_iter().map(&project).collect();
+            points
+                .windows(2)
+                .map(|chord| distance(chord[0], chord[1]))
+                .collect()
+        }
+    };
+    let mut lengths = Vec::with_capacity(ts.len());
+    lengths.push(0.);
+    for step in steps {
+        lengths.push(lengths[lengths.len() - 1] + step);
+    }
+    Ok(lengths)
+}
+
+/// The `t` every `step` along the curve, and the last of `ts`, where
+/// `lengths` is the distance along the curve at each of `ts`.
+pub fn by_length(ts: &[f32], lengths: &[f32], step: f32) -> Vec<f32> {
+    let total = lengths.last().copied().unwrap_or_default();
+    let mut samples = vec![];
+    let mut i = 0;
+    // multiples of `step` rather than a running sum, which would drift
+    let targets = (0..)
+        .map(|k| k as f32 * step)
+        .take_while(|target| *target < total);
+    for target in targets {
+        while lengths[i + 1] < target {
+            i += 1;
+        }
+        let stretch = lengths[i + 1] - lengths[i];
+        let t = if stretch > 0. {
+            ts[i] + (ts[i + 1] - ts[i]) * (target - lengths[i]) / stretch
+        } else {
+            ts[i]
+        };
+        samples.push(t);
+    }
+    samples.extend(ts.last());
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::bytecode::Compiled;
+    use std::f32::consts::TAU;
+
+    /// The points of the draw in `src`, taken every `step` along the curve
+    /// over `T` from 0 to `TAU` once `project`ed, and the length of the curve.
+    fn spaced(
+        src: &str,
+        step: f32,
+        project: impl Fn((f32, f32)) -> (f32, f32) + Copy,
+    ) -> (Vec<(f32, f32)>, f32) {
+        let Compiled {
+            environment,
+            x,
+            y,
+            program,
+        } = Compiled::draw(src);
+        let program = program.unwrap();
+        let ts: Vec<f32> = (0..=LENGTH_SEGMENTS)
+            .map(|i| TAU * i as f32 / LENGTH_SEGMENTS as f32)
+            .collect();
+        let lengths = arc_lengths(&ts, (&x, &y), "T", &environment, &program, project).unwrap();
+        let points = program
+            .eval_points(&by_length(&ts, &lengths, step))
+            .into_iter()
+            .map(project)
+            .collect();
+        (points, *lengths.last().unwrap())
+    }
+
+    fn distances(points: &[(f32, f32)]) -> Vec<f32> {
+        points
+            .windows(2)
+            .map(|pair| (pair[1].0 - pair[0].0).hypot(pair[1].1 - pair[0].1))
+            .collect()
+    }
+
+    #[test]
+    fn measures_a_circle() {
+        let circle = "for T from 0 to 1 step 0.1 draw (100 * Cos(T), 100 * Sin(T));";
+        let (_, length) = spaced(circle, 10., |xy| xy);
+        assert!((length - 100. * TAU).abs() < 0.01, "{}", length);
+        // Max has no derivative, so the chords measure it
+        let chords = "for T from 0 to 1 step 0.1 draw (Max(100 * Cos(T), -1000), 100 * Sin(T));";
+        let (_, length) = spaced(chords, 10., |xy| xy);
+        assert!((length - 100. * TAU).abs() < 0.01, "{}", length);
+    }
+
+    #[test]
+    fn measures_through_impure_funcs() {
+        let src = "fn w() = 3 * T; for T from 0 to 1 step 0.1 draw (Cos(w()), Sin(w()));";
+        let (_, length) = spaced(src, 0.1, |xy| xy);
+        assert!((length - 3. * TAU).abs() < 0.01, "{}", length);
+    }
+
+    #[test]
+    fn spaces_samples_evenly_once_scaled() {
+        // as with `scale is (100, 40);`, which turns the circle into an ellipse
+        let circle = "for T from 0 to 1 step 0.1 draw (Cos(T), Sin(T));";
+        let (points, length) = spaced(circle, 5., |(x, y)| (x * 100., y * 40.));
+        let distances = distances(&points);
+        assert_eq!(distances.len(), (length / 5.).ceil() as usize);
+        // the chords fall a little short of the arcs where the ellipse bends
+        // the most
+        let (last, rest) = distances.split_last().unwrap();
+        for distance in rest {
+            assert!((distance - 5.).abs() < 0.05, "{}", distance);
+        }
+        assert!(*last <= 5.05, "{}", last);
+    }
+}
@@ -1,123 +1,475 @@
-use crate::ast::{Expr, Stmt};
-use crate::interpreter::environment::{Environment, IdentTy};
+use crate::ast::{Expr, ExprKind, Span, Step, Stmt, StmtKind};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::error::Result;
+use crate::interpreter::derivative::DERIVATIVE;
+use crate::interpreter::environment::{Environment, Function, IdentTy};
 use crate::interpreter::visit::{walk_expr, walk_stmt, Visitor};
+use std::collections::HashSet;
 
-#[derive(Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ValueType {
     Callable,
     Const,
     Var,
+    /// The type of an expression that already produced an error. It is
+    /// accepted everywhere so a single mistake is only reported once.
+    Poisoned,
+}
+
+impl ValueType {
+    /// Type of a value computed from both `self` and `other`.
+    pub fn join(self, other: ValueType) -> ValueType {
+        match (self, other) {
+            (ValueType::Poisoned, _) | (_, ValueType::Poisoned) => ValueType::Poisoned,
+            (ValueType::Const, ValueType::Const) => ValueType::Const,
+            (ValueType::Var, ValueType::Var) => ValueType::Var,
+            (ValueType::Const, ValueType::Var) | (ValueType::Var, ValueType::Const) => {
+                ValueType::Var
+            }
+            (ValueType::Callable, _) | (_, ValueType::Callable) => ValueType::Callable,
+        }
+    }
 }
 
 #[derive(Default)]
-pub struct StaticChecker {
+pub struct StaticChecker<'ast> {
     pub stack: Vec<ValueType>,
-    pub environment: Environment,
+    pub environment: Environment<'ast>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Names bound by a `let` or `fn` whose value failed to check.
+    pub poisoned: HashSet<String>,
+    /// The `fn` whose body is being checked, to report recursion.
+    pub current_fn: Option<&'ast str>,
+    /// Params of `current_fn`, which [`DERIVATIVE`] can derive in.
+    pub params: Vec<&'ast str>,
+    /// Names bound by a `let`, which are substituted before [`DERIVATIVE`]
+    /// could derive in them.
+    pub lets: HashSet<String>,
 }
 
-impl StaticChecker {
-    pub fn check(&mut self, src: &[Stmt]) {
+impl<'ast> StaticChecker<'ast> {
+    pub fn new(environment: Environment<'ast>) -> Self {
+        Self {
+            environment,
+            ..Self::default()
+        }
+    }
+
+    pub fn check(&mut self, src: &[Stmt<'ast>]) -> Vec<Diagnostic> {
         for stmt in src.iter() {
-            self.visit_stmt(stmt)
+            // the checker never fails, every problem ends up in diagnostics
+            let _ = self.visit_stmt(stmt);
+            self.stack.clear();
         }
+        std::mem::take(&mut self.diagnostics)
     }
-}
 
-impl<'ast> Visitor<'ast> for StaticChecker {
-    fn visit_expr(&mut self, expr: &Expr<'ast>) {
-        walk_expr(self, expr);
-        match expr {
-            Expr::Binary(_, _, _) => {
-                let rhs = self.stack.pop().unwrap();
-                let lhs = self.stack.pop().unwrap();
-                match (lhs, rhs) {
-                    (ValueType::Const, ValueType::Const) => self.stack.push(ValueType::Const),
-                    (ValueType::Var, ValueType::Var) => self.stack.push(ValueType::Var),
-                    (ValueType::Const, ValueType::Var) | (ValueType::Var, ValueType::Const) => {
-                        self.stack.push(ValueType::Var)
-                    }
-                    (ValueType::Callable, _) | (_, ValueType::Callable) => {
-                        self.stack.push(ValueType::Callable)
-                    }
+    /// Checks a standalone expression and returns its type.
+    pub fn check_expr(&mut self, expr: &Expr<'ast>) -> (ValueType, Vec<Diagnostic>) {
+        let _ = self.visit_expr(expr);
+        let ty = self.pop();
+        self.stack.clear();
+        (ty, std::mem::take(&mut self.diagnostics))
+    }
+
+    fn pop(&mut self) -> ValueType {
+        self.stack.pop().unwrap_or(ValueType::Poisoned)
+    }
+
+    fn error(&mut self, message: &str, span: Span, label: &str) {
+        self.diagnostics
+            .push(Diagnostic::spanned(message, span, label));
+    }
+
+    /// What `var` is instead, when [`DERIVATIVE`] can't derive in it.
+    fn underivable(&self, var: &Expr) -> Option<&'static str> {
+        let ExprKind::Ident(ident) = &var.kind else {
+            return Some("expect an ident");
+        };
+        if self.params.contains(&ident.name) {
+            return None;
+        }
+        match self.environment.lookup.get(ident.name) {
+            Some(IdentTy::Var) if self.lets.contains(ident.name) => Some("a let binding"),
+            Some(IdentTy::Var) => None,
+            Some(IdentTy::Const) => Some("a Const"),
+            Some(IdentTy::Func) => Some("a func"),
+            None => Some("unknown ident"),
+        }
+    }
+
+    fn declare(&mut self, ident_expr: &Expr<'ast>, what: &str) -> Option<&'ast str> {
+        let ExprKind::Ident(ident) = &ident_expr.kind else {
+            self.error(
+                &format!("Expect an Ident after {}.", what),
+                ident_expr.span,
+                "expect an ident",
+            );
+            return None;
+        };
+        let name = ident.name;
+        if self.environment.lookup.contains_key(name) || self.poisoned.contains(name) {
+            self.error("Ident already defined.", ident_expr.span, "redefinition");
+            return None;
+        }
+        Some(name)
+    }
+
+    fn check_fn(&mut self, ident_expr: &Expr<'ast>, params: &[Expr<'ast>], body: &Expr<'ast>) {
+        let name = self.declare(ident_expr, "Fn");
+
+        let mut names: Vec<&'ast str> = vec![];
+        for param in params {
+            if let Some(name) = self.declare(param, "Fn") {
+                if names.contains(&name) {
+                    self.error("Duplicate param.", param.span, "params must be unique");
+                } else {
+                    names.push(name);
                 }
             }
-            Expr::Unary(_, _) => {
-                let operand = self.stack.pop().unwrap();
+        }
+
+        // params are checked as Const, so a Const body means the result only
+        // depends on the arguments
+        for param in names.iter() {
+            self.environment
+                .lookup
+                .insert(param.to_string(), IdentTy::Const);
+        }
+        self.current_fn = name;
+        self.params = names.clone();
+        let diagnostics = self.diagnostics.len();
+        let _ = self.visit_expr(body);
+        let ty = self.pop();
+        let ty = self.value(body, ty);
+        self.current_fn = None;
+        self.params.clear();
+        for param in names.iter() {
+            self.environment.lookup.remove(*param);
+        }
+
+        let Some(name) = name else {
+            return;
+        };
+        if ty == ValueType::Poisoned
+            || self.diagnostics.len() != diagnostics
+            || names.len() != params.len()
+        {
+            self.poisoned.insert(name.into());
+            return;
+        }
+        self.environment.define(
+            name,
+            Function {
+                params: names,
+                body: body.clone(),
+                pure: ty == ValueType::Const,
+            },
+        );
+    }
+
+    /// `ty` of `expr` where a value is expected, a bare func is reported and
+    /// poisons it.
+    fn value(&mut self, expr: &Expr, ty: ValueType) -> ValueType {
+        let mut inner = expr;
+        while let ExprKind::Grouping(grouped) = &inner.kind {
+            inner = grouped;
+        }
+        let ExprKind::Ident(ident) = &inner.kind else {
+            return ty;
+        };
+        if ty == ValueType::Callable
+            && matches!(self.environment.lookup.get(ident.name), Some(IdentTy::Func))
+        {
+            self.error("Expect a value, found a func.", expr.span, "not a value");
+            return ValueType::Poisoned;
+        }
+        ty
+    }
+
+    fn expect_const(&mut self, ty: ValueType, expr: &Expr, message: &str) {
+        if ty != ValueType::Const && ty != ValueType::Poisoned {
+            self.error(message, expr.span, "expect a Const");
+        }
+    }
+}
+
+impl<'ast> Visitor<'ast> for StaticChecker<'ast> {
+    fn visit_expr(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        walk_expr(self, expr)?;
+        match &expr.kind {
+            ExprKind::Binary(lhs_expr, _, rhs_expr) => {
+                let rhs = self.pop();
+                let rhs = self.value(rhs_expr, rhs);
+                let lhs = self.pop();
+                let lhs = self.value(lhs_expr, lhs);
+                self.stack.push(lhs.join(rhs));
+            }
+            ExprKind::If(cond_expr, then_expr, other_expr) => {
+                let other = self.pop();
+                let other = self.value(other_expr, other);
+                let then = self.pop();
+                let then = self.value(then_expr, then);
+                let cond = self.pop();
+                let cond = self.value(cond_expr, cond);
+                self.stack.push(cond.join(then).join(other));
+            }
+            ExprKind::Unary(_, operand_expr) => {
+                let operand = self.pop();
+                let operand = self.value(operand_expr, operand);
                 self.stack.push(operand);
             }
-            Expr::Call(_, args) => {
+            ExprKind::Call(callee, args) => {
                 let mut args_ty = vec![];
                 for _ in args {
-                    let ty = self.stack.pop().unwrap();
+                    let ty = self.pop();
                     args_ty.push(ty);
                 }
-                if args_ty.len() != 1 {
-                    panic!("Too much arguments.")
-                }
+                let callee_ty = self.pop();
 
-                let callee = self.stack.pop().unwrap();
-                if callee != ValueType::Callable {
-                    panic!("Expect a valid func in call-expr.")
-                }
-                if args_ty.iter().all(|ty| ValueType::Const == *ty) {
+                let name = match &callee.kind {
+                    ExprKind::Ident(ident) => ident.name,
+                    _ => "",
+                };
+                let arity = self.environment.arity(name);
+                let func_arg = args.iter().find(|arg| match &arg.kind {
+                    ExprKind::Ident(ident) => {
+                        matches!(self.environment.lookup.get(ident.name), Some(IdentTy::Func))
+                    }
+                    _ => false,
+                });
+
+                if callee_ty == ValueType::Poisoned || args_ty.contains(&ValueType::Poisoned) {
+                    self.stack.push(ValueType::Poisoned);
+                } else if callee_ty != ValueType::Callable {
+                    self.error(
+                        "Expect a valid func in call-expr.",
+                        callee.span,
+                        "not a func",
+                    );
+                    self.stack.push(ValueType::Poisoned);
+                } else if let Some(arity) = arity.filter(|arity| !arity.accepts(args_ty.len())) {
+                    self.error(
+                        &format!(
+                            "{} takes {} argument(s) but {} were supplied.",
+                            name,
+                            arity,
+                            args_ty.len()
+                        ),
+                        expr.span,
+                        "wrong number of arguments",
+                    );
+                    self.stack.push(ValueType::Poisoned);
+                } else if let Some((var, found)) = args
+                    .get(1)
+                    .filter(|_| name == DERIVATIVE)
+                    .and_then(|var| Some((var, self.underivable(var)?)))
+                {
+                    self.error("Expect T or a param to derive in.", var.span, found);
+                    self.stack.push(ValueType::Poisoned);
+                } else if let Some(arg) = func_arg {
+                    self.error(
+                        "Expect a value as argument, found a func.",
+                        arg.span,
+                        "not a value",
+                    );
+                    self.stack.push(ValueType::Poisoned);
+                } else if args_ty.iter().all(|ty| ValueType::Const == *ty)
+                    && self
+                        .environment
+                        .functions
+                        .get(name)
+                        .is_none_or(|function| function.pure)
+                {
                     self.stack.push(ValueType::Const);
                 } else {
                     self.stack.push(ValueType::Callable);
                 }
             }
-            Expr::Grouping(_) => {
-                let inner = self.stack.pop().unwrap();
+            ExprKind::Grouping(_) => {
+                let inner = self.pop();
                 self.stack.push(inner);
             }
-            Expr::Lit(_) => self.stack.push(ValueType::Const),
-            Expr::Ident(ident) => {
+            ExprKind::Lit(_) => self.stack.push(ValueType::Const),
+            ExprKind::Ident(ident) => {
                 let name = ident.name;
                 let lookup = self.environment.lookup.get(name);
-                if let Some(ty) = lookup {
+                if self.poisoned.contains(name) {
+                    self.stack.push(ValueType::Poisoned);
+                } else if let Some(ty) = lookup {
                     match ty {
                         IdentTy::Var => self.stack.push(ValueType::Var),
                         IdentTy::Func => self.stack.push(ValueType::Callable),
                         IdentTy::Const => self.stack.push(ValueType::Const),
                     }
+                } else if self.current_fn == Some(name) {
+                    self.error("Recursive call is not allowed.", expr.span, "recursion");
+                    self.stack.push(ValueType::Poisoned);
                 } else {
-                    panic!("Expect a valid ident.")
+                    self.error("Expect a valid ident.", expr.span, "unknown ident");
+                    let mut known: Vec<&str> =
+                        self.environment.lookup.keys().map(|k| k.as_str()).collect();
+                    known.sort_unstable();
+                    self.diagnostics.push(
+                        Diagnostic::new(format!("known idents are: {}", known.join(", ")))
+                            .with_severity(Severity::Note),
+                    );
+                    self.stack.push(ValueType::Poisoned);
                 }
             }
         }
+        Ok(())
     }
 
-    fn visit_stmt(&mut self, stmt: &Stmt<'ast>) {
-        walk_stmt(self, stmt);
-        match stmt {
-            Stmt::Draw(_, _, _, _, _, _) => {
-                let _y = self.stack.pop().unwrap();
-                let _x = self.stack.pop().unwrap();
-                let step = self.stack.pop().unwrap();
-                let to = self.stack.pop().unwrap();
-                let from = self.stack.pop().unwrap();
-                let ident = self.stack.pop().unwrap();
+    fn visit_stmt(&mut self, stmt: &Stmt<'ast>) -> Result<()> {
+        if let StmtKind::Fn(ident, params, body) = &stmt.kind {
+            // params have to be in scope before the body is walked
+            self.check_fn(ident, params, body);
+            return Ok(());
+        }
+        walk_stmt(self, stmt)?;
+        match &stmt.kind {
+            StmtKind::Draw(ident_expr, from_expr, to_expr, step, x_expr, y_expr) => {
+                let y = self.pop();
+                let y = self.value(y_expr, y);
+                let x = self.pop();
+                let x = self.value(x_expr, x);
+                let step = match step {
+                    Step::Fixed(step_expr) | Step::Length(step_expr) => {
+                        Some((self.pop(), step_expr))
+                    }
+                    Step::Auto => None,
+                };
+                let to = self.pop();
+                let from = self.pop();
+                let ident = self.pop();
 
-                if ident != ValueType::Var {
-                    panic!("Expect an Ident after For.")
+                if ident != ValueType::Var && ident != ValueType::Poisoned {
+                    self.error(
+                        "Expect an Ident after For.",
+                        ident_expr.span,
+                        "expect a Var",
+                    );
                 }
-                if from != ValueType::Const || to != ValueType::Const || step != ValueType::Const {
-                    panic!("Expect a Const in <from>/<to>/<step>")
+                let message = "Expect a Const in <from>/<to>/<step>";
+                self.expect_const(from, from_expr, message);
+                self.expect_const(to, to_expr, message);
+                if let Some((step, step_expr)) = step {
+                    self.expect_const(step, step_expr, message);
                 }
-            }
-            Stmt::Rot(_) => {
-                let expr = self.stack.pop().unwrap();
-                if expr != ValueType::Const {
-                    panic!("Expect a Const in Rot")
+
+                if x == ValueType::Const && y == ValueType::Const {
+                    let span = x_expr.span.start_pos().span(&y_expr.span.end_pos());
+                    self.diagnostics.push(
+                        Diagnostic::spanned(
+                            "Both coordinates of Draw are Const.",
+                            span,
+                            "every sample lands on the same point",
+                        )
+                        .with_severity(Severity::Warning),
+                    );
                 }
             }
-            Stmt::Scale(_, _) | Stmt::Origin(_, _) => {
-                let rhs = self.stack.pop().unwrap();
-                let lhs = self.stack.pop().unwrap();
-                if lhs != ValueType::Const || rhs != ValueType::Const {
-                    panic!("Expect a Const in Scale/Origin")
+            StmtKind::Rot(expr) => {
+                let ty = self.pop();
+                self.expect_const(ty, expr, "Expect a Const in Rot");
+            }
+            StmtKind::Size(expr) => {
+                let ty = self.pop();
+                self.expect_const(ty, expr, "Expect a Const in Size");
+            }
+            StmtKind::Scale(lhs_expr, rhs_expr) | StmtKind::Origin(lhs_expr, rhs_expr) => {
+                let rhs = self.pop();
+                let lhs = self.pop();
+                let message = "Expect a Const in Scale/Origin";
+                self.expect_const(lhs, lhs_expr, message);
+                self.expect_const(rhs, rhs_expr, message);
+            }
+            StmtKind::Canvas(width_expr, height_expr) => {
+                let height = self.pop();
+                let width = self.pop();
+                let message = "Expect a Const in Canvas";
+                self.expect_const(width, width_expr, message);
+                self.expect_const(height, height_expr, message);
+            }
+            StmtKind::Background(r_expr, g_expr, b_expr)
+            | StmtKind::Color(r_expr, g_expr, b_expr) => {
+                let b = self.pop();
+                let g = self.pop();
+                let r = self.pop();
+                let message = "Expect a Const in Background/Color";
+                self.expect_const(r, r_expr, message);
+                self.expect_const(g, g_expr, message);
+                self.expect_const(b, b_expr, message);
+            }
+            StmtKind::Let(ident_expr, value_expr) => {
+                let value = self.pop();
+                let value = self.value(value_expr, value);
+                let Some(name) = self.declare(ident_expr, "Let") else {
+                    return Ok(());
+                };
+                self.lets.insert(name.into());
+                match value {
+                    ValueType::Const => {
+                        self.environment.lookup.insert(name.into(), IdentTy::Const);
+                    }
+                    ValueType::Var | ValueType::Callable => {
+                        self.environment.lookup.insert(name.into(), IdentTy::Var);
+                    }
+                    ValueType::Poisoned => {
+                        self.poisoned.insert(name.into());
+                    }
                 }
             }
-            Stmt::EOI => {}
+            StmtKind::Style(_) | StmtKind::Fn(_, _, _) | StmtKind::EOI => {}
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Rule, SrcParser};
+    use pest::Parser;
+
+    /// The messages and labels of the errors in `src`.
+    fn errors(src: &str) -> Vec<(String, String)> {
+        let pairs = SrcParser::parse(Rule::source, src).unwrap();
+        let source = SrcParser.source(pairs).unwrap();
+        StaticChecker::default()
+            .check(&source)
+            .into_iter()
+            .filter(|diagnostic| matches!(diagnostic.severity, Severity::Error))
+            .map(|diagnostic| (diagnostic.message, diagnostic.label))
+            .collect()
+    }
+
+    fn underivable(label: &str) -> Vec<(String, String)> {
+        vec![("Expect T or a param to derive in.".into(), label.into())]
+    }
+
+    #[test]
+    fn derives_in_t_and_params() {
+        assert!(errors(
+            "fn g(a) = D(a ** 3, a); for T from 0 to 1 step 0.1 draw (g(T), D(T * T, T));"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn rejects_deriving_in_anything_else() {
+        let draw = |d: &str| format!("for T from 0 to 1 step 0.1 draw ({}, T);", d);
+        assert_eq!(errors(&draw("D(PI * T, PI)")), underivable("a Const"));
+        assert_eq!(
+            errors(&format!("let c = 2; {}", draw("D(c * T, c)"))),
+            underivable("a Const")
+        );
+        assert_eq!(
+            errors(&format!("let a = T * 2; {}", draw("D(a * a, a)"))),
+            underivable("a let binding")
+        );
+        assert_eq!(errors(&draw("D(T, Sin)")), underivable("a func"));
+        assert_eq!(errors(&draw("D(T, 2)")), underivable("expect an ident"));
     }
 }
@@ -1,58 +1,81 @@
-use crate::ast::{Expr, Stmt};
+use crate::ast::{Expr, ExprKind, Step, Stmt, StmtKind};
+use crate::error::Result;
 
 macro_rules! walk_list {
     ($visitor: expr, $method: ident, $list: expr) => {{
         for elem in $list {
-            $visitor.$method(elem)
+            $visitor.$method(elem)?
         }
     }};
 }
 
 pub trait Visitor<'ast>: Sized {
-    fn visit_expr(&mut self, expr: &Expr<'ast>) {
-        walk_expr(self, expr);
+    fn visit_expr(&mut self, expr: &Expr<'ast>) -> Result<()> {
+        walk_expr(self, expr)
     }
 
-    fn visit_stmt(&mut self, stmt: &Stmt<'ast>) {
-        walk_stmt(self, stmt);
+    fn visit_stmt(&mut self, stmt: &Stmt<'ast>) -> Result<()> {
+        walk_stmt(self, stmt)
     }
 }
 
-pub fn walk_expr<'ast, V: Visitor<'ast>>(visitor: &mut V, expr: &Expr<'ast>) {
-    match expr {
-        Expr::Binary(lhs, _, rhs) => {
-            visitor.visit_expr(lhs);
-            visitor.visit_expr(rhs);
+pub fn walk_expr<'ast, V: Visitor<'ast>>(visitor: &mut V, expr: &Expr<'ast>) -> Result<()> {
+    match &expr.kind {
+        ExprKind::Binary(lhs, _, rhs) => {
+            visitor.visit_expr(lhs)?;
+            visitor.visit_expr(rhs)?;
         }
-        Expr::Unary(_, operand) => {
-            visitor.visit_expr(operand);
+        ExprKind::Unary(_, operand) => {
+            visitor.visit_expr(operand)?;
         }
-        Expr::Call(callee, args) => {
-            visitor.visit_expr(callee);
+        ExprKind::Call(callee, args) => {
+            visitor.visit_expr(callee)?;
             walk_list!(visitor, visit_expr, args);
         }
-        Expr::Grouping(expr) => {
-            visitor.visit_expr(expr);
+        ExprKind::Grouping(expr) => {
+            visitor.visit_expr(expr)?;
         }
-        Expr::Lit(_) => {}
-        Expr::Ident(_) => {}
+        ExprKind::If(cond, then, other) => {
+            visitor.visit_expr(cond)?;
+            visitor.visit_expr(then)?;
+            visitor.visit_expr(other)?;
+        }
+        ExprKind::Lit(_) => {}
+        ExprKind::Ident(_) => {}
     }
+    Ok(())
 }
 
-pub fn walk_stmt<'ast, V: Visitor<'ast>>(visitor: &mut V, stmt: &Stmt<'ast>) {
-    match stmt {
-        Stmt::Draw(a0, a1, a2, a3, a4, a5) => {
-            walk_list!(visitor, visit_expr, vec![a0, a1, a2, a3, a4, a5]);
+pub fn walk_stmt<'ast, V: Visitor<'ast>>(visitor: &mut V, stmt: &Stmt<'ast>) -> Result<()> {
+    match &stmt.kind {
+        StmtKind::Draw(a0, a1, a2, a3, a4, a5) => {
+            walk_list!(visitor, visit_expr, [a0, a1, a2]);
+            match a3 {
+                Step::Fixed(step) | Step::Length(step) => visitor.visit_expr(step)?,
+                Step::Auto => {}
+            }
+            walk_list!(visitor, visit_expr, [a4, a5]);
+        }
+        StmtKind::Rot(a0) => visitor.visit_expr(a0)?,
+        StmtKind::Scale(a0, a1) => {
+            visitor.visit_expr(a0)?;
+            visitor.visit_expr(a1)?;
         }
-        Stmt::Rot(a0) => visitor.visit_expr(a0),
-        Stmt::Scale(a0, a1) => {
-            visitor.visit_expr(a0);
-            visitor.visit_expr(a1);
+        StmtKind::Origin(a0, a1) => {
+            visitor.visit_expr(a0)?;
+            visitor.visit_expr(a1)?;
         }
-        Stmt::Origin(a0, a1) => {
-            visitor.visit_expr(a0);
-            visitor.visit_expr(a1);
+        StmtKind::Canvas(a0, a1) => {
+            visitor.visit_expr(a0)?;
+            visitor.visit_expr(a1)?;
         }
-        Stmt::EOI => {}
+        StmtKind::Background(a0, a1, a2) => walk_list!(visitor, visit_expr, [a0, a1, a2]),
+        StmtKind::Color(a0, a1, a2) => walk_list!(visitor, visit_expr, [a0, a1, a2]),
+        StmtKind::Size(a0) => visitor.visit_expr(a0)?,
+        StmtKind::Style(_) => {}
+        StmtKind::Let(_, value) => visitor.visit_expr(value)?,
+        StmtKind::Fn(_, _, body) => visitor.visit_expr(body)?,
+        StmtKind::EOI => {}
     }
+    Ok(())
 }
@@ -1,3 +1,11 @@
 pub mod ast;
+pub mod diagnostic;
+pub mod error;
+pub mod formatter;
 pub mod interpreter;
 pub mod parser;
+
+pub use error::{Error, Result};
+pub use interpreter::environment::{Arity, Environment};
+pub use interpreter::registry::FunctionRegistry;
+pub use interpreter::RenderConfig;
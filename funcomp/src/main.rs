@@ -1,19 +1,418 @@
-use std::{env, io};
-use std::fs::File;
-use funcomp::interpreter::static_checker::StaticChecker;
+use funcomp::ast::{Stmt, StmtKind};
+use funcomp::diagnostic::Diagnostic;
+use funcomp::interpreter::static_checker::{StaticChecker, ValueType};
 use funcomp::interpreter::Interpreter;
 use funcomp::parser::{Rule, SrcParser};
+use funcomp::{formatter, Error, RenderConfig, Result};
+use funcomp_plot::{Canvas, Format, RGBColor};
 use pest::Parser;
+use std::fmt::Display;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::{env, io, process};
+
+mod repl;
+
+const USAGE: &str = "\
+usage: funcomp <command> [<args>]
+
+commands:
+    render <source> [<options>]  draw the script into an image
+    check <source>               only run the static checks
+    fmt <source> [--check]       print the script in canonical form, or with
+                                 --check only fail if it isn't in it already
+    dump-ast <source>            print the parsed statements
+    dump-folded <source>         print the statements once constants are folded,
+                                 and what folding changed to stderr
+    eval <expr>                  fold a single expression and print it
+    repl                         read statements interactively
+    help                         print this help
+
+<source> is the path of a script, or - to read it from stdin, which is also
+where it is read from when it is left out. `funcomp <source>` is short for
+`funcomp render <source>` when <source> has a `.` or `/` in it, or is a file.
+
+render options:
+    -o, --output <path>     defaults to the source path with the image extension
+    --format png|svg        defaults to the one matching the output extension
+    --force                 overwrite the output if it exists
+    --size <W>x<H>          canvas size in pixels
+    --dpi <scale>           scales the canvas and the pen
+    --background <RRGGBB>   background color
+    --margin <px>           room left around the plot
+
+exit status:
+    0    success
+    1    the script has errors, or isn't formatted for fmt --check
+    2    the command line is wrong or the source can't be read
+    3    the image can't be written";
+
+/// What the command line asks for.
+enum Command {
+    Render(RenderArgs),
+    Check(Option<String>),
+    Fmt {
+        path: Option<String>,
+        /// Only tell whether the source is formatted already.
+        check: bool,
+    },
+    DumpAst(Option<String>),
+    DumpFolded(Option<String>),
+    Eval(String),
+    Repl,
+    Help,
+}
+
+struct RenderArgs {
+    /// `None` or `-` for stdin.
+    path: Option<String>,
+    /// Defaults to the script's path with the image extension.
+    output: Option<PathBuf>,
+    /// Defaults to the one matching the output extension.
+    format: Option<Format>,
+    /// Whether an existing output file may be overwritten.
+    force: bool,
+    canvas: CanvasArgs,
+}
+
+/// Canvas settings given on the command line. They win over the ones a
+/// script sets with `canvas is`/`background is`.
+#[derive(Default)]
+struct CanvasArgs {
+    size: Option<(u32, u32)>,
+    dpi_scale: Option<f32>,
+    background: Option<RGBColor>,
+    margin: Option<u32>,
+}
+
+impl CanvasArgs {
+    fn apply(&self, canvas: &mut Canvas) {
+        if let Some(size) = self.size {
+            canvas.size = size;
+        }
+        if let Some(dpi_scale) = self.dpi_scale {
+            canvas.dpi_scale = dpi_scale;
+        }
+        if let Some(background) = self.background {
+            canvas.background = background;
+        }
+        if let Some(margin) = self.margin {
+            canvas.margin = margin;
+        }
+    }
+}
+
+fn usage(msg: impl Display) -> Error {
+    Error::Usage(format!("{}\nrun `funcomp help` to see the usage", msg))
+}
+
+fn invalid(flag: &str, value: &str) -> Error {
+    usage(format!("invalid value for {}: {}", flag, value))
+}
+
+fn parse_size(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once(['x', 'X'])?;
+    let size = (width.parse().ok()?, height.parse().ok()?);
+    (size.0 > 0 && size.1 > 0).then_some(size)
+}
+
+fn parse_color(value: &str) -> Option<RGBColor> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(RGBColor(channel(0)?, channel(2)?, channel(4)?))
+}
+
+fn parse_format(value: &str) -> Option<Format> {
+    match value.to_ascii_lowercase().as_str() {
+        "png" => Some(Format::Png),
+        "svg" => Some(Format::Svg),
+        _ => None,
+    }
+}
+
+const COMMANDS: &[&str] = &[
+    "render",
+    "check",
+    "fmt",
+    "dump-ast",
+    "dump-folded",
+    "eval",
+    "repl",
+    "help",
+];
+
+/// Whether `arg` reads as the source or an option of an implicit render,
+/// rather than a mistyped command.
+fn is_render_arg(arg: &str) -> bool {
+    arg.starts_with('-') || arg.contains(['.', '/']) || Path::new(arg).is_file()
+}
+
+fn is_help(arg: &str) -> bool {
+    arg == "-h" || arg == "--help"
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Command> {
+    let mut args = args.skip(1).peekable();
+    let command = match args.peek().map(String::as_str) {
+        None => return Err(usage("expect a command")),
+        Some(arg) if is_help(arg) || arg == "help" => return Ok(Command::Help),
+        Some("repl") => Command::Repl,
+        Some("eval") => {
+            args.next();
+            let expr = args
+                .next()
+                .ok_or_else(|| usage("eval expects an expression"))?;
+            if is_help(&expr) {
+                return Ok(Command::Help);
+            }
+            if let Some(arg) = args.next() {
+                return Err(usage(format!("unexpected argument {}", arg)));
+            }
+            return Ok(Command::Eval(expr));
+        }
+        Some("check") => Command::Check(None),
+        Some("fmt") => Command::Fmt {
+            path: None,
+            check: false,
+        },
+        Some("dump-ast") => Command::DumpAst(None),
+        Some("dump-folded") => Command::DumpFolded(None),
+        Some("render") => {
+            args.next();
+            return parse_render(args);
+        }
+        Some(arg) if is_render_arg(arg) => return parse_render(args),
+        Some(arg) => {
+            return Err(usage(format!(
+                "unknown command '{}', expect one of: {}",
+                arg,
+                COMMANDS.join(", ")
+            )))
+        }
+    };
+    args.next();
+    let mut path = None;
+    let mut check = false;
+    for arg in args {
+        if is_help(&arg) {
+            return Ok(Command::Help);
+        }
+        if arg == "--check" && matches!(command, Command::Fmt { .. }) {
+            check = true;
+            continue;
+        }
+        if arg.starts_with('-') && arg != "-" {
+            return Err(usage(format!("unknown option {}", arg)));
+        }
+        if path.replace(arg).is_some() {
+            return Err(usage("expect one source file"));
+        }
+    }
+    Ok(match command {
+        Command::Check(_) => Command::Check(path),
+        Command::Fmt { .. } => Command::Fmt { path, check },
+        Command::DumpAst(_) => Command::DumpAst(path),
+        Command::DumpFolded(_) => Command::DumpFolded(path),
+        command => command,
+    })
+}
+
+fn parse_render(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let mut path = None;
+    let mut output = None;
+    let mut format = None;
+    let mut force = false;
+    let mut canvas = CanvasArgs::default();
+    while let Some(arg) = args.next() {
+        if is_help(&arg) {
+            return Ok(Command::Help);
+        }
+        if arg == "--force" {
+            force = true;
+            continue;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            if path.replace(arg).is_some() {
+                return Err(usage("expect one source file"));
+            }
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| usage(format!("{} expects a value", arg)))?;
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(PathBuf::from(value)),
+            "--format" => format = Some(parse_format(&value).ok_or_else(|| invalid(&arg, &value))?),
+            "--size" => {
+                canvas.size = Some(parse_size(&value).ok_or_else(|| invalid(&arg, &value))?)
+            }
+            "--dpi" => {
+                let scale = value.parse().ok().filter(|scale: &f32| *scale > 0.);
+                canvas.dpi_scale = Some(scale.ok_or_else(|| invalid(&arg, &value))?);
+            }
+            "--background" => {
+                canvas.background = Some(parse_color(&value).ok_or_else(|| invalid(&arg, &value))?)
+            }
+            "--margin" => canvas.margin = Some(value.parse().map_err(|_| invalid(&arg, &value))?),
+            _ => return Err(usage(format!("unknown option {}", arg))),
+        }
+    }
+    Ok(Command::Render(RenderArgs {
+        path,
+        output,
+        format,
+        force,
+        canvas,
+    }))
+}
+
+/// The path given for the source, `None` when it is read from stdin.
+fn source_path(path: &Option<String>) -> Option<&str> {
+    path.as_deref().filter(|path| *path != "-")
+}
+
+fn read_source(path: &Option<String>) -> Result<String> {
+    match source_path(path) {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| Error::Usage(format!("{}: {}", path, e)))?;
+            io::read_to_string(file).map_err(|e| Error::Usage(format!("{}: {}", path, e)))
+        }
+        None => io::read_to_string(io::stdin()).map_err(|e| Error::Usage(format!("stdin: {}", e))),
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Stmt<'_>>> {
+    let pairs = SrcParser::parse(Rule::source, source)?;
+    SrcParser.source(pairs)
+}
+
+/// Runs the static checks, printing the warnings, and fails with the errors
+/// if there are any.
+fn check(source: &[Stmt]) -> Result<()> {
+    let diagnostics = StaticChecker::default().check(source);
+    report(diagnostics)
+}
+
+fn report(diagnostics: Vec<Diagnostic>) -> Result<()> {
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(Error::Static(diagnostics));
+    }
+    for diagnostic in diagnostics {
+        eprintln!("{}\n", diagnostic);
+    }
+    Ok(())
+}
+
+fn render(args: RenderArgs) -> Result<()> {
+    let RenderArgs {
+        path,
+        output,
+        format,
+        force,
+        canvas,
+    } = args;
+    let output = output.unwrap_or_else(|| {
+        let extension = format.unwrap_or_default().extension();
+        match source_path(&path) {
+            Some(path) => Path::new(path).with_extension(extension),
+            None => Path::new("out").with_extension(extension),
+        }
+    });
+    let mut config = RenderConfig::new(output);
+    if let Some(format) = format {
+        config = config.with_format(format);
+    }
+    if !force && config.output.exists() {
+        return Err(usage(format!(
+            "{} already exists, pass --force to overwrite it",
+            config.output.display()
+        )));
+    }
+    let source = read_source(&path)?;
+    let source = parse(&source)?;
+    check(&source)?;
+    let mut interpreter = Interpreter::new(config).accept(&source)?;
+    canvas.apply(&mut interpreter.config.canvas);
+    interpreter.interpret()?;
+    Ok(())
+}
+
+/// Returns the exit status when the command ran but didn't succeed.
+fn run() -> Result<i32> {
+    match parse_args(env::args())? {
+        Command::Render(args) => render(args)?,
+        Command::Check(path) => check(&parse(&read_source(&path)?)?)?,
+        Command::Fmt { path, check } => {
+            let source = read_source(&path)?;
+            if !check {
+                print!("{}", formatter::format(&source)?);
+            } else if !formatter::is_formatted(&source)? {
+                eprintln!(
+                    "{} is not formatted",
+                    source_path(&path).unwrap_or("<stdin>")
+                );
+                return Ok(1);
+            }
+        }
+        Command::DumpAst(path) => {
+            for stmt in parse(&read_source(&path)?)? {
+                println!("{:#?}", stmt);
+            }
+        }
+        Command::DumpFolded(path) => {
+            let source = read_source(&path)?;
+            let source = parse(&source)?;
+            check(&source)?;
+            let interpreter = Interpreter::new(RenderConfig::default()).accept(&source)?;
+            for stmt in interpreter.statements.iter() {
+                if !matches!(stmt.kind, StmtKind::EOI) {
+                    println!("{}", stmt);
+                }
+            }
+            for change in interpreter.changes.iter() {
+                eprintln!("{}", change);
+            }
+        }
+        Command::Eval(expr) => {
+            let pairs = SrcParser::parse(Rule::expression, &expr)?;
+            let expr = SrcParser.expression(pairs)?;
+            let (ty, diagnostics) = StaticChecker::default().check_expr(&expr);
+            report(diagnostics)?;
+            if !matches!(ty, ValueType::Const) {
+                return Err(Error::Static(vec![Diagnostic::spanned(
+                    "Expect a Const to eval.",
+                    expr.span,
+                    "not a constant",
+                )]));
+            }
+            let value = Interpreter::new(RenderConfig::default()).fold_expr(&expr)?;
+            println!("{}", value);
+        }
+        Command::Repl => repl::run(RenderConfig::default())?,
+        Command::Help => println!("{}", USAGE),
+    }
+    Ok(0)
+}
+
+/// See the exit status in [`USAGE`].
+fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::Parse(_) | Error::Static(_) | Error::Runtime(_) => 1,
+        Error::Usage(_) => 2,
+        Error::Render(_) => 3,
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let path = &args[1];
-    let file = File::open(path).unwrap();
-    let source = io::read_to_string(file).unwrap();
-    let mut static_checker = StaticChecker::default();
-    let interpreter = Interpreter::default();
-    let pairs = SrcParser::parse(Rule::source, &source).unwrap();
-    let source = SrcParser.source(pairs);
-    static_checker.check(&source);
-    interpreter.accept(&source).interpret();
+    match run() {
+        Ok(0) => {}
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(exit_code(&e));
+        }
+    }
 }
@@ -1,179 +1,350 @@
-use crate::ast::{BinOp, Expr, Ident, Lit, Stmt, UnOp};
+use crate::ast::{BinOp, Expr, ExprKind, Ident, Lit, Step, Stmt, StmtKind, UnOp};
+use crate::error::{Error, Result};
 use crate::p;
+use funcomp_plot::Style;
 use pest::iterators::{Pair, Pairs};
 use pest_derive::Parser;
 
 macro_rules! single_expr_stmt {
     ($expr: ident) => {
-        pub fn $expr(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Stmt {
-            let ident = self.expr(pairs.next().unwrap().into_inner());
-            Stmt::$expr(p!(ident))
+        pub fn $expr(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+            let mut pairs = pair.into_inner();
+            let ident = self.expr(next(&mut pairs, stringify!($expr))?)?;
+            Ok(StmtKind::$expr(p!(ident)))
         }
     };
 }
 
 macro_rules! dual_expr_stmt {
     ($expr: ident) => {
-        pub fn $expr(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Stmt {
-            let lhs = self.expr(pairs.next().unwrap().into_inner());
-            let rhs = self.expr(pairs.next().unwrap().into_inner());
-            Stmt::$expr(p!(lhs), p!(rhs))
+        pub fn $expr(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+            let mut pairs = pair.into_inner();
+            let lhs = self.expr(next(&mut pairs, stringify!($expr))?)?;
+            let rhs = self.expr(next(&mut pairs, stringify!($expr))?)?;
+            Ok(StmtKind::$expr(p!(lhs), p!(rhs)))
         }
     };
 }
 
 macro_rules! dual_operand_expr {
     ($expr: ident, $lit: literal, $upstream: ident) => {
-        pub fn $expr(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Expr {
-            let lit = ::std::string::String::from($lit);
-            if let Some(expr) = pairs.next() {
-                match expr.as_rule() {
-                    Rule::$upstream => {
-                        let mut expr = self.$upstream(expr.into_inner());
-                        while let Some(_) = pairs.peek() {
-                            let op = self.binop(pairs.next().unwrap());
-                            let rhs = self.$upstream(pairs.next().unwrap().into_inner());
-                            expr = Expr::binary(p!(expr), op, p!(rhs));
-                        }
-                        expr
+        pub fn $expr(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+            let mut pairs = pair.into_inner();
+            let expr = next(&mut pairs, $lit)?;
+            match expr.as_rule() {
+                Rule::$upstream => {
+                    let mut expr = self.$upstream(expr)?;
+                    while pairs.peek().is_some() {
+                        let op = self.binop(next(&mut pairs, $lit)?)?;
+                        let rhs = self.$upstream(next(&mut pairs, $lit)?)?;
+                        let span = expr.span.start_pos().span(&rhs.span.end_pos());
+                        expr = Expr::new(ExprKind::binary(p!(expr), op, p!(rhs)), span);
                     }
-                    _ => panic!("Invalid {} type.", lit),
+                    Ok(expr)
                 }
-            } else {
-                panic!("Invalid {}.", lit)
+                _ => Err(Error::Parse(format!("Invalid {} type.", $lit))),
             }
         }
     };
 }
 
+fn next<'ast>(pairs: &mut Pairs<'ast, Rule>, what: &str) -> Result<Pair<'ast, Rule>> {
+    pairs
+        .next()
+        .ok_or_else(|| Error::Parse(format!("Invalid {}.", what)))
+}
+
 #[derive(Parser)]
 #[grammar = "expr.pest"]
 pub struct SrcParser;
 
 impl<'ast> SrcParser {
-    pub fn binop(&self, op: Pair<Rule>) -> BinOp {
+    pub fn binop(&self, op: Pair<Rule>) -> Result<BinOp> {
         match op.as_rule() {
-            Rule::plus => BinOp::Plus,
-            Rule::minus => BinOp::Minus,
-            Rule::asterisk => BinOp::Asterisk,
-            Rule::slash => BinOp::Slash,
-            _ => panic!("Invalid bin op."),
+            Rule::plus => Ok(BinOp::Plus),
+            Rule::minus => Ok(BinOp::Minus),
+            Rule::asterisk => Ok(BinOp::Asterisk),
+            Rule::slash => Ok(BinOp::Slash),
+            Rule::percent => Ok(BinOp::Percent),
+            Rule::caret => Ok(BinOp::Caret),
+            Rule::lt => Ok(BinOp::Lt),
+            Rule::le => Ok(BinOp::Le),
+            Rule::gt => Ok(BinOp::Gt),
+            Rule::ge => Ok(BinOp::Ge),
+            Rule::eq => Ok(BinOp::Eq),
+            Rule::ne => Ok(BinOp::Ne),
+            Rule::and => Ok(BinOp::And),
+            Rule::or => Ok(BinOp::Or),
+            _ => Err(Error::Parse("Invalid bin op.".into())),
         }
     }
 
-    pub fn unop(&self, op: Pair<Rule>) -> UnOp {
+    pub fn unop(&self, op: Pair<Rule>) -> Result<UnOp> {
         match op.as_rule() {
-            Rule::plus => UnOp::Pos,
-            Rule::minus => UnOp::Neg,
-            _ => panic!("Invalid un op."),
+            Rule::plus => Ok(UnOp::Pos),
+            Rule::minus => Ok(UnOp::Neg),
+            Rule::not => Ok(UnOp::Not),
+            _ => Err(Error::Parse("Invalid un op.".into())),
         }
     }
 
-    pub fn source(&'ast self, pairs: Pairs<'ast, Rule>) -> Vec<Stmt> {
+    pub fn source(&'ast self, pairs: Pairs<'ast, Rule>) -> Result<Vec<Stmt<'ast>>> {
         let mut ret = vec![];
         for statement in pairs {
             match statement.as_rule() {
                 Rule::stmt => {
-                    ret.push(self.stmt(statement.into_inner()));
+                    ret.push(self.stmt(statement)?);
                 }
                 Rule::EOI => {
-                    ret.push(Stmt::eoi());
+                    ret.push(Stmt::new(StmtKind::eoi(), statement.as_span()));
                 }
-                _ => panic!("Invalid statement type."),
+                _ => return Err(Error::Parse("Invalid statement type.".into())),
             }
         }
-        ret
+        Ok(ret)
     }
 
-    pub fn stmt(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Stmt {
-        if let Some(statement) = pairs.next() {
-            match statement.as_rule() {
-                Rule::draw => self.draw(statement.into_inner()),
-                Rule::rot => self.rot(statement.into_inner()),
-                Rule::scale => self.scale(statement.into_inner()),
-                Rule::origin => self.origin(statement.into_inner()),
-                _ => panic!("Invalid statement type."),
-            }
-        } else {
-            panic!("Invalid statement.")
-        }
+    pub fn expression(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Result<Expr<'ast>> {
+        self.expr(next(&mut pairs, "expression")?)
+    }
+
+    pub fn stmt(&'ast self, pair: Pair<'ast, Rule>) -> Result<Stmt<'ast>> {
+        let span = pair.as_span();
+        let statement = next(&mut pair.into_inner(), "statement")?;
+        let kind = match statement.as_rule() {
+            Rule::draw => self.draw(statement)?,
+            Rule::rot => self.rot(statement)?,
+            Rule::scale => self.scale(statement)?,
+            Rule::origin => self.origin(statement)?,
+            Rule::canvas => self.canvas(statement)?,
+            Rule::background => self.background(statement)?,
+            Rule::color => self.color(statement)?,
+            Rule::size => self.size(statement)?,
+            Rule::style => self.style(statement)?,
+            Rule::let_stmt => self.let_stmt(statement)?,
+            Rule::func => self.func(statement)?,
+            _ => return Err(Error::Parse("Invalid statement type.".into())),
+        };
+        Ok(Stmt::new(kind, span))
     }
 
     single_expr_stmt! {rot}
     dual_expr_stmt! {scale}
     dual_expr_stmt! {origin}
+    dual_expr_stmt! {canvas}
+    single_expr_stmt! {size}
 
-    pub fn draw(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Stmt {
-        let ident = self.ident(pairs.next().unwrap());
-        let from = self.expr(pairs.next().unwrap().into_inner());
-        let to = self.expr(pairs.next().unwrap().into_inner());
-        let step = self.expr(pairs.next().unwrap().into_inner());
-        let x = self.expr(pairs.next().unwrap().into_inner());
-        let y = self.expr(pairs.next().unwrap().into_inner());
-        Stmt::Draw(p!(ident), p!(from), p!(to), p!(step), p!(x), p!(y))
+    pub fn background(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+        let mut pairs = pair.into_inner();
+        let r = self.expr(next(&mut pairs, "background")?)?;
+        let g = self.expr(next(&mut pairs, "background")?)?;
+        let b = self.expr(next(&mut pairs, "background")?)?;
+        Ok(StmtKind::background(p!(r), p!(g), p!(b)))
     }
 
-    dual_operand_expr! {expr, "expr", factor}
-    dual_operand_expr! {factor, "factor", unary}
+    pub fn color(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+        let mut pairs = pair.into_inner();
+        let r = self.expr(next(&mut pairs, "color")?)?;
+        let g = self.expr(next(&mut pairs, "color")?)?;
+        let b = self.expr(next(&mut pairs, "color")?)?;
+        Ok(StmtKind::color(p!(r), p!(g), p!(b)))
+    }
 
-    pub fn unary(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Expr {
-        if let Some(leftest) = pairs.peek() {
-            match leftest.as_rule() {
-                Rule::plus | Rule::minus => {
-                    let op = self.unop(pairs.next().unwrap());
-                    let rhs = self.unary(pairs.next().unwrap().into_inner());
-                    Expr::unary(op, p!(rhs))
-                }
-                Rule::call => {
-                    let mut call = pairs.next().unwrap().into_inner();
-                    let callee = self.primary(call.next().unwrap().into_inner());
-                    let args = if let Some(args) = call.next() {
-                        self.arguments(args.into_inner())
-                    } else {
-                        vec![]
-                    };
-                    Expr::call(p!(callee), args)
-                }
-                Rule::primary => self.primary(pairs.next().unwrap().into_inner()),
-                _ => panic!("Invalid unary type: {:?}.", leftest),
+    pub fn style(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+        let style = next(&mut pair.into_inner(), "style")?;
+        let style = match style.as_str().to_ascii_lowercase().as_str() {
+            "line" => Style::Line,
+            "dots" => Style::Dots,
+            "dashed" => Style::Dashed,
+            _ => return Err(Error::Parse(format!("Invalid style: {}.", style.as_str()))),
+        };
+        Ok(StmtKind::style(style))
+    }
+
+    pub fn draw(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+        let mut pairs = pair.into_inner();
+        let ident = self.ident(next(&mut pairs, "draw")?);
+        let from = self.expr(next(&mut pairs, "draw")?)?;
+        let to = self.expr(next(&mut pairs, "draw")?)?;
+        let step = self.step(next(&mut pairs, "draw")?)?;
+        let x = self.expr(next(&mut pairs, "draw")?)?;
+        let y = self.expr(next(&mut pairs, "draw")?)?;
+        Ok(StmtKind::Draw(
+            p!(ident),
+            p!(from),
+            p!(to),
+            step,
+            p!(x),
+            p!(y),
+        ))
+    }
+
+    pub fn step(&'ast self, pair: Pair<'ast, Rule>) -> Result<Step<'ast>> {
+        let step = next(&mut pair.into_inner(), "step")?;
+        match step.as_rule() {
+            Rule::auto => Ok(Step::auto()),
+            Rule::length => {
+                let mut pairs = step.into_inner();
+                next(&mut pairs, "step")?;
+                Ok(Step::length(p!(self.expr(next(&mut pairs, "step")?)?)))
             }
+            Rule::expr => Ok(Step::fixed(p!(self.expr(step)?))),
+            _ => Err(Error::Parse("Invalid step type.".into())),
+        }
+    }
+
+    pub fn let_stmt(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+        let mut pairs = pair.into_inner();
+        let ident = self.ident(next(&mut pairs, "let")?);
+        let value = self.expr(next(&mut pairs, "let")?)?;
+        Ok(StmtKind::r#let(p!(ident), p!(value)))
+    }
+
+    pub fn func(&'ast self, pair: Pair<'ast, Rule>) -> Result<StmtKind<'ast>> {
+        let mut pairs = pair.into_inner();
+        let ident = self.ident(next(&mut pairs, "fn")?);
+        let mut body = next(&mut pairs, "fn")?;
+        let params = if body.as_rule() == Rule::params {
+            let params = body.into_inner().map(|pair| self.ident(pair)).collect();
+            body = next(&mut pairs, "fn")?;
+            params
         } else {
-            panic!("Invalid unary.")
+            vec![]
+        };
+        let body = self.expr(body)?;
+        Ok(StmtKind::r#fn(p!(ident), params, p!(body)))
+    }
+
+    pub fn expr(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let expr = next(&mut pair.into_inner(), "expr")?;
+        match expr.as_rule() {
+            Rule::cond => self.cond(expr),
+            Rule::disjunction => self.disjunction(expr),
+            _ => Err(Error::Parse("Invalid expr type.".into())),
         }
     }
 
-    pub fn primary(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Expr {
-        if let Some(primary) = pairs.peek() {
-            match primary.as_rule() {
-                Rule::number => {
-                    let lit = Lit::Number(pairs.next().unwrap().as_str().parse().unwrap());
-                    Expr::lit(lit)
-                }
-                Rule::grouping => self.grouping(pairs.next().unwrap().into_inner()),
-                Rule::ident => self.ident(pairs.next().unwrap()),
-                _ => panic!("Invalid primary type."),
+    pub fn cond(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let span = pair.as_span();
+        let mut pairs = pair.into_inner();
+        let cond = self.expr(next(&mut pairs, "if")?)?;
+        let then = self.expr(next(&mut pairs, "if")?)?;
+        let other = self.expr(next(&mut pairs, "if")?)?;
+        Ok(Expr::new(
+            ExprKind::r#if(p!(cond), p!(then), p!(other)),
+            span,
+        ))
+    }
+
+    dual_operand_expr! {disjunction, "disjunction", conjunction}
+    dual_operand_expr! {conjunction, "conjunction", negation}
+
+    pub fn negation(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let span = pair.as_span();
+        let mut pairs = pair.into_inner();
+        let leftest = next(&mut pairs, "negation")?;
+        match leftest.as_rule() {
+            Rule::not => {
+                let op = self.unop(leftest)?;
+                let operand = self.negation(next(&mut pairs, "negation")?)?;
+                Ok(Expr::new(ExprKind::unary(op, p!(operand)), span))
             }
+            Rule::comparison => self.comparison(leftest),
+            _ => Err(Error::Parse("Invalid negation type.".into())),
+        }
+    }
+
+    dual_operand_expr! {comparison, "comparison", sum}
+    dual_operand_expr! {sum, "sum", factor}
+    dual_operand_expr! {factor, "factor", unary}
+
+    pub fn unary(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let span = pair.as_span();
+        let mut pairs = pair.into_inner();
+        let leftest = next(&mut pairs, "unary")?;
+        match leftest.as_rule() {
+            Rule::plus | Rule::minus => {
+                let op = self.unop(leftest)?;
+                let rhs = self.unary(next(&mut pairs, "unary")?)?;
+                Ok(Expr::new(ExprKind::unary(op, p!(rhs)), span))
+            }
+            Rule::power => self.power(leftest),
+            _ => Err(Error::Parse(format!("Invalid unary type: {:?}.", leftest))),
+        }
+    }
+
+    pub fn power(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let span = pair.as_span();
+        let mut pairs = pair.into_inner();
+        let base = next(&mut pairs, "power")?;
+        let base = match base.as_rule() {
+            Rule::call => self.call(base)?,
+            Rule::primary => self.primary(base)?,
+            _ => return Err(Error::Parse("Invalid power type.".into())),
+        };
+        if pairs.peek().is_none() {
+            return Ok(base);
+        }
+        let op = self.binop(next(&mut pairs, "power")?)?;
+        // the exponent is a unary, which makes `**` right-associative
+        let exponent = self.unary(next(&mut pairs, "power")?)?;
+        Ok(Expr::new(
+            ExprKind::binary(p!(base), op, p!(exponent)),
+            span,
+        ))
+    }
+
+    pub fn call(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let span = pair.as_span();
+        let mut pairs = pair.into_inner();
+        let callee = self.primary(next(&mut pairs, "call")?)?;
+        let args = if let Some(args) = pairs.next() {
+            self.arguments(args)?
         } else {
-            panic!("Invalid primary.")
+            vec![]
+        };
+        Ok(Expr::new(ExprKind::call(p!(callee), args), span))
+    }
+
+    pub fn primary(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let primary = next(&mut pair.into_inner(), "primary")?;
+        match primary.as_rule() {
+            Rule::number => {
+                let number: f32 = primary
+                    .as_str()
+                    .parse()
+                    .map_err(|_| Error::Parse(format!("Invalid number: {}.", primary.as_str())))?;
+                if !number.is_finite() {
+                    return Err(Error::Parse(format!(
+                        "Number out of range: {}.",
+                        primary.as_str()
+                    )));
+                }
+                Ok(Expr::new(
+                    ExprKind::lit(Lit::Number(number)),
+                    primary.as_span(),
+                ))
+            }
+            Rule::grouping => self.grouping(primary),
+            Rule::ident => Ok(self.ident(primary)),
+            _ => Err(Error::Parse("Invalid primary type.".into())),
         }
     }
 
-    pub fn ident(&'ast self, pair: Pair<'ast, Rule>) -> Expr {
+    pub fn ident(&'ast self, pair: Pair<'ast, Rule>) -> Expr<'ast> {
         let ident = Ident {
             name: pair.as_str(),
         };
-        Expr::ident(ident)
+        Expr::new(ExprKind::ident(ident), pair.as_span())
     }
 
-    pub fn arguments(&'ast self, pairs: Pairs<'ast, Rule>) -> Vec<Expr> {
-        pairs.map(|pair| self.expr(pair.into_inner())).collect()
+    pub fn arguments(&'ast self, pair: Pair<'ast, Rule>) -> Result<Vec<Expr<'ast>>> {
+        pair.into_inner().map(|pair| self.expr(pair)).collect()
     }
 
-    pub fn grouping(&'ast self, mut pairs: Pairs<'ast, Rule>) -> Expr {
-        if let Some(inner) = pairs.next() {
-            self.expr(inner.into_inner())
-        } else {
-            panic!("Invalid grouping.")
-        }
+    pub fn grouping(&'ast self, pair: Pair<'ast, Rule>) -> Result<Expr<'ast>> {
+        let span = pair.as_span();
+        let inner = self.expr(next(&mut pair.into_inner(), "grouping")?)?;
+        Ok(Expr::new(inner.kind, span))
     }
 }
@@ -0,0 +1,286 @@
+use funcomp::ast::{Stmt, StmtKind};
+use funcomp::diagnostic::Diagnostic;
+use funcomp::interpreter::static_checker::StaticChecker;
+use funcomp::interpreter::Interpreter;
+use funcomp::parser::{Rule, SrcParser};
+use funcomp::{Error, RenderConfig, Result};
+use funcomp_plot::Format;
+use pest::error::InputLocation;
+use pest::Parser;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::slice;
+use typed_arena::Arena;
+
+const HELP: &str = "\
+Enter statements to run them, or an expression to see it folded.
+A statement that isn't finished yet continues on the next line, an empty
+line drops it.
+
+:state            show the drawing state and what is defined
+:render [path]    draw every statement so far, into out.png by default
+:render! [path]   the same, overwriting the file if it exists
+:reset            forget every statement
+:help             show this help
+:quit             leave";
+
+/// What the input typed so far amounts to.
+enum Input {
+    Statements,
+    Expression,
+    Incomplete,
+    Invalid(Error),
+}
+
+fn classify(src: &str) -> Input {
+    let error = match SrcParser::parse(Rule::source, src) {
+        Ok(_) => return Input::Statements,
+        Err(error) => error,
+    };
+    if SrcParser::parse(Rule::expression, src).is_ok() {
+        return Input::Expression;
+    }
+    let at = match error.location {
+        InputLocation::Pos(at) => at,
+        InputLocation::Span((at, _)) => at,
+    };
+    if at >= src.trim_end().len() {
+        Input::Incomplete
+    } else {
+        Input::Invalid(error.into())
+    }
+}
+
+/// What to do once a `:command` ran.
+enum Flow {
+    Continue,
+    Reset,
+    Quit,
+}
+
+/// Reads statements from stdin until it ends or `:quit` is entered.
+pub fn run(mut config: RenderConfig) -> Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        // a reset starts over with new inputs, dropping the old ones
+        let inputs = Arena::new();
+        match Repl::new(config, &inputs).run(&mut lines)? {
+            Some(kept) => config = kept,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Statements are checked, folded and applied as soon as they are entered,
+/// so the environment and the drawing state carry over from one input to
+/// the next.
+///
+/// Every accepted input is kept in `inputs` until the session is reset: the
+/// environment keeps borrowing the source text of `let` and `fn` bodies.
+pub struct Repl<'src> {
+    inputs: &'src Arena<String>,
+    checker: StaticChecker<'src>,
+    interpreter: Interpreter<'src>,
+    /// Lines of a statement that isn't complete yet.
+    pending: String,
+}
+
+impl<'src> Repl<'src> {
+    pub fn new(config: RenderConfig, inputs: &'src Arena<String>) -> Self {
+        Self {
+            inputs,
+            checker: StaticChecker::default(),
+            interpreter: Interpreter::new(config),
+            pending: String::new(),
+        }
+    }
+
+    /// Runs the session on `lines`, giving back the config when it's reset.
+    fn run(
+        &mut self,
+        lines: &mut impl Iterator<Item = io::Result<String>>,
+    ) -> Result<Option<RenderConfig>> {
+        loop {
+            print!(
+                "{}",
+                if self.pending.is_empty() {
+                    "> "
+                } else {
+                    "... "
+                }
+            );
+            io::stdout()
+                .flush()
+                .map_err(|e| Error::Usage(e.to_string()))?;
+            let Some(line) = lines.next() else {
+                println!();
+                return Ok(None);
+            };
+            let line = line.map_err(|e| Error::Usage(e.to_string()))?;
+            if self.pending.is_empty() && line.trim().starts_with(':') {
+                match self.command(line.trim()) {
+                    Flow::Continue => continue,
+                    Flow::Reset => return Ok(Some(self.interpreter.config.clone())),
+                    Flow::Quit => return Ok(None),
+                }
+            }
+            if let Err(e) = self.line(&line) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Flow {
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            ":quit" | ":q" => return Flow::Quit,
+            ":help" | ":h" => println!("{}", HELP),
+            ":state" => self.print_state(),
+            ":reset" => return Flow::Reset,
+            ":render" | ":render!" => {
+                let path = arg.trim();
+                if !path.is_empty() {
+                    let config = &mut self.interpreter.config;
+                    config.output = PathBuf::from(path);
+                    config.format = Format::from_path(&config.output);
+                }
+                let output = &self.interpreter.config.output;
+                if command == ":render" && output.exists() {
+                    eprintln!(
+                        "{} already exists, use :render! to overwrite it",
+                        output.display()
+                    );
+                    return Flow::Continue;
+                }
+                match self.interpreter.render_output() {
+                    Ok(()) => println!("wrote {}", self.interpreter.config.output.display()),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            _ => eprintln!("unknown command {}, try :help", command),
+        }
+        Flow::Continue
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        if line.trim().is_empty() {
+            if self.pending.is_empty() {
+                return Ok(());
+            }
+            let pending = std::mem::take(&mut self.pending);
+            return match classify(&pending) {
+                Input::Invalid(e) => Err(e),
+                _ => Err(Error::Parse("unfinished input dropped.".into())),
+            };
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        match classify(&self.pending) {
+            Input::Incomplete => Ok(()),
+            Input::Invalid(e) => {
+                self.pending.clear();
+                Err(e)
+            }
+            Input::Statements => {
+                let src = self.inputs.alloc(std::mem::take(&mut self.pending));
+                self.statements(src)
+            }
+            Input::Expression => {
+                let src = self.inputs.alloc(std::mem::take(&mut self.pending));
+                self.expression(src)
+            }
+        }
+    }
+
+    fn statements(&mut self, src: &'src str) -> Result<()> {
+        let pairs = SrcParser::parse(Rule::source, src)?;
+        let source = SrcParser.source(pairs)?;
+        for stmt in source.iter() {
+            if matches!(stmt.kind, StmtKind::EOI) {
+                continue;
+            }
+            let diagnostics = self.checker.check(slice::from_ref(stmt));
+            let failed = diagnostics.iter().any(Diagnostic::is_error);
+            for diagnostic in diagnostics {
+                eprintln!("{}\n", diagnostic);
+            }
+            if failed {
+                // a name that failed to check may be defined again right away
+                self.checker.poisoned.clear();
+                return Ok(());
+            }
+            let folded = self.interpreter.fold(slice::from_ref(stmt))?.to_vec();
+            self.print_definition(stmt);
+            for stmt in folded.iter() {
+                self.interpreter.state.apply(stmt)?;
+                println!("{}", stmt);
+            }
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, src: &'src str) -> Result<()> {
+        let pairs = SrcParser::parse(Rule::expression, src)?;
+        let expr = SrcParser.expression(pairs)?;
+        let (ty, diagnostics) = self.checker.check_expr(&expr);
+        let failed = diagnostics.iter().any(Diagnostic::is_error);
+        for diagnostic in diagnostics {
+            eprintln!("{}\n", diagnostic);
+        }
+        if failed {
+            return Ok(());
+        }
+        let folded = self.interpreter.fold_expr(&expr)?;
+        println!("{} : {:?}", folded, ty);
+        Ok(())
+    }
+
+    /// `let` and `fn` only add to the environment, shows what they added.
+    fn print_definition(&self, stmt: &Stmt) {
+        let environment = &self.interpreter.environment;
+        match &stmt.kind {
+            StmtKind::Let(ident, _) => {
+                let name = ident.to_string();
+                if let Some(value) = environment.bindings.get(&name) {
+                    println!("let {} = {};", name, value);
+                }
+            }
+            StmtKind::Fn(ident, _, _) => {
+                let name = ident.to_string();
+                if let Some(function) = environment.functions.get(&name) {
+                    println!(
+                        "fn {}({}) = {};",
+                        name,
+                        function.params.join(", "),
+                        function.body
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn print_state(&self) {
+        let state = &self.interpreter.state;
+        let canvas = &self.interpreter.config.canvas;
+        let environment = &self.interpreter.environment;
+        println!("rot        {}", state.rot);
+        println!("scale      ({}, {})", state.scale.0, state.scale.1);
+        println!("origin     ({}, {})", state.origin.0, state.origin.1);
+        println!(
+            "color      ({}, {}, {})",
+            state.color.0, state.color.1, state.color.2
+        );
+        println!("size       {}", state.size);
+        println!("style      {}", state.style);
+        println!("canvas     ({}, {})", canvas.size.0, canvas.size.1);
+        let mut lets: Vec<&str> = environment.bindings.keys().map(String::as_str).collect();
+        lets.sort();
+        println!("let        {}", lets.join(", "));
+        let mut fns: Vec<&str> = environment.functions.keys().map(String::as_str).collect();
+        fns.sort();
+        println!("fn         {}", fns.join(", "));
+        println!("statements {}", self.interpreter.statements.len());
+    }
+}
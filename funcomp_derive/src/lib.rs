@@ -3,6 +3,17 @@ use quote::quote;
 use syn::spanned::Spanned;
 use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident};
 
+/// Lower-cased constructor name for a variant, escaped as a raw identifier
+/// when it collides with a keyword (e.g. `Let` -> `r#let`).
+fn constructor_ident(var: &Ident) -> Ident {
+    let name = var.to_string().to_lowercase();
+    if syn::parse_str::<Ident>(&name).is_ok() {
+        Ident::new(&name, var.span())
+    } else {
+        Ident::new_raw(&name, var.span())
+    }
+}
+
 #[proc_macro_derive(ItemKind)]
 pub fn derive(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -22,22 +33,24 @@ pub fn derive(input: TokenStream) -> TokenStream {
 
     if let Data::Enum(data_enum) = item_data {
         for var in data_enum.variants {
-            let mut var_field_idents = vec![];
-            let mut var_tys = vec![];
-            let span = var.span();
             match var.fields {
                 Fields::Unit => {
-                    unit_ident_lower.push(Ident::new(&var.ident.to_string().to_lowercase(), span));
+                    unit_ident_lower.push(constructor_ident(&var.ident));
                     unit_ident.push(var.ident);
                 }
                 Fields::Unnamed(fields) => {
-                    var_ident_lower.push(Ident::new(&var.ident.to_string().to_lowercase(), span));
+                    let mut var_field_idents = vec![];
+                    let mut var_tys = vec![];
+                    var_ident_lower.push(constructor_ident(&var.ident));
                     var_ident.push(var.ident);
                     for (counter, field) in fields.unnamed.into_iter().enumerate() {
                         let span = field.ty.span();
                         var_tys.push(field.ty);
                         var_field_idents.push(Ident::new(&format!("a{counter}"), span));
                     }
+                    // in step with `var_ident`, unit variants have no entry here
+                    var_fields_ident.push(var_field_idents);
+                    var_fields.push(var_tys);
                 }
                 Fields::Named(_) => {
                     return Error::new(
@@ -48,9 +61,6 @@ pub fn derive(input: TokenStream) -> TokenStream {
                     .into();
                 }
             }
-
-            var_fields_ident.push(var_field_idents);
-            var_fields.push(var_tys);
         }
     } else {
         return Error::new(item_ident.span(), "ItemKind can only used with enum.")
@@ -0,0 +1,297 @@
+use funcomp::ast::{Expr, ExprKind, Stmt, StmtKind};
+use funcomp::diagnostic::{self, Severity};
+use funcomp::interpreter::environment::IdentTy;
+use funcomp::interpreter::static_checker::{StaticChecker, ValueType};
+use funcomp::interpreter::visit::{walk_expr, walk_stmt, Visitor};
+use funcomp::parser::{Rule, SrcParser};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, Position, Range,
+};
+use pest::error::{InputLocation, LineColLocation};
+use pest::Parser;
+
+/// Keywords of the language, in lowercase.
+pub const KEYWORDS: &[&str] = &[
+    "for",
+    "from",
+    "to",
+    "step",
+    "draw",
+    "auto",
+    "by",
+    "length",
+    "is",
+    "rot",
+    "scale",
+    "origin",
+    "canvas",
+    "background",
+    "color",
+    "size",
+    "style",
+    "line",
+    "dots",
+    "dashed",
+    "let",
+    "fn",
+    "if",
+    "then",
+    "else",
+    "and",
+    "or",
+    "not",
+];
+
+/// Position of the byte `offset` of `src`, LSP counts characters in UTF-16
+/// code units.
+pub fn position(src: &str, offset: usize) -> Position {
+    let before = &src[..offset];
+    let line_start = before.rfind('\n').map_or(0, |at| at + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+/// Byte offset of `position` in `src`, clamped to the end of its line.
+pub fn offset(src: &str, position: Position) -> Option<usize> {
+    let line_start = if position.line == 0 {
+        0
+    } else {
+        src.match_indices('\n').nth(position.line as usize - 1)?.0 + 1
+    };
+    let line = src[line_start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (at, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return Some(line_start + at);
+        }
+        units += c.len_utf16();
+    }
+    Some(line_start + line.len())
+}
+
+pub fn range(src: &str, start: usize, end: usize) -> Range {
+    Range::new(position(src, start), position(src, end))
+}
+
+/// Position of the 1-based `line` and character `col` reported by pest.
+fn line_col(src: &str, (line, col): (usize, usize)) -> Position {
+    let text = src.split('\n').nth(line - 1).unwrap_or_default();
+    let character = text
+        .chars()
+        .take(col - 1)
+        .map(char::len_utf16)
+        .sum::<usize>();
+    Position::new(line as u32 - 1, character as u32)
+}
+
+/// Errors of the parser, or else everything the [`StaticChecker`] reports.
+pub fn diagnostics(src: &str) -> Vec<Diagnostic> {
+    let pairs = match SrcParser::parse(Rule::source, src) {
+        Ok(pairs) => pairs,
+        Err(error) => {
+            let range = match error.line_col {
+                LineColLocation::Pos(at) => {
+                    let start = line_col(src, at);
+                    Range::new(start, Position::new(start.line, start.character + 1))
+                }
+                LineColLocation::Span(start, end) => {
+                    Range::new(line_col(src, start), line_col(src, end))
+                }
+            };
+            return vec![error_at(range, error.variant.message().to_string())];
+        }
+    };
+    let source = match SrcParser.source(pairs) {
+        Ok(source) => source,
+        Err(error) => return vec![error_at(Range::default(), error.to_string())],
+    };
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    for diagnostic in StaticChecker::default().check(&source) {
+        let Some(snippet) = &diagnostic.snippet else {
+            // notes explain the diagnostic before them
+            if let Some(last) = diagnostics.last_mut() {
+                last.message.push_str(&format!("\n{}", diagnostic.message));
+            }
+            continue;
+        };
+        let start = line_col(src, (snippet.line, snippet.col));
+        let end = line_col(src, (snippet.line, snippet.col + snippet.len));
+        diagnostics.push(Diagnostic {
+            range: Range::new(start, end),
+            severity: Some(match diagnostic.severity {
+                Severity::Error => DiagnosticSeverity::ERROR,
+                Severity::Warning => DiagnosticSeverity::WARNING,
+                Severity::Note => DiagnosticSeverity::INFORMATION,
+            }),
+            source: Some("funcomp".into()),
+            message: message(&diagnostic),
+            ..Diagnostic::default()
+        });
+    }
+    diagnostics
+}
+
+fn message(diagnostic: &diagnostic::Diagnostic) -> String {
+    if diagnostic.label.is_empty() {
+        diagnostic.message.clone()
+    } else {
+        format!("{} ({})", diagnostic.message, diagnostic.label)
+    }
+}
+
+fn error_at(range: Range, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("funcomp".into()),
+        message,
+        ..Diagnostic::default()
+    }
+}
+
+/// Finds the innermost expression around an offset, including the names
+/// declared by `let` and `fn`, which the walk doesn't visit.
+struct Innermost<'ast> {
+    offset: usize,
+    found: Option<Expr<'ast>>,
+}
+
+impl Innermost<'_> {
+    fn contains(&self, expr: &Expr) -> bool {
+        expr.span.start() <= self.offset && self.offset <= expr.span.end()
+    }
+}
+
+impl<'ast> Visitor<'ast> for Innermost<'ast> {
+    fn visit_expr(&mut self, expr: &Expr<'ast>) -> funcomp::Result<()> {
+        if self.contains(expr) {
+            self.found = Some(expr.clone());
+            walk_expr(self, expr)?;
+        }
+        Ok(())
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt<'ast>) -> funcomp::Result<()> {
+        match &stmt.kind {
+            StmtKind::Let(ident, _) => self.visit_expr(ident)?,
+            StmtKind::Fn(ident, params, _) => {
+                self.visit_expr(ident)?;
+                for param in params {
+                    self.visit_expr(param)?;
+                }
+            }
+            _ => {}
+        }
+        walk_stmt(self, stmt)
+    }
+}
+
+/// The expression at `offset` and its [`ValueType`] as Markdown.
+pub fn hover(src: &str, offset: usize) -> Option<(Range, String)> {
+    let pairs = SrcParser::parse(Rule::source, src).ok()?;
+    let source = SrcParser.source(pairs).ok()?;
+    let index = source
+        .iter()
+        .position(|stmt| stmt.span.start() <= offset && offset <= stmt.span.end())?;
+    let mut innermost = Innermost {
+        offset,
+        found: None,
+    };
+    innermost.visit_stmt(&source[index]).ok()?;
+    let expr = innermost.found?;
+
+    let mut checker = StaticChecker::default();
+    checker.check(&source[..=index]);
+    if let StmtKind::Fn(_, params, _) = &source[index].kind {
+        // the way the checker sees them inside the body
+        for param in params {
+            if let ExprKind::Ident(ident) = &param.kind {
+                checker
+                    .environment
+                    .lookup
+                    .insert(ident.name.into(), IdentTy::Const);
+            }
+        }
+    }
+    let (ty, _) = checker.check_expr(&expr);
+    let mut text = format!("```funcomp\n{}\n```\n\n{:?}", expr, ty);
+    if let ExprKind::Ident(ident) = &expr.kind {
+        if let Some(arity) = checker.environment.arity(ident.name) {
+            text.push_str(&format!(", takes {} argument(s)", arity));
+        }
+    }
+    if ty == ValueType::Poisoned {
+        text.push_str(", it has errors");
+    }
+    Some((range(src, expr.span.start(), expr.span.end()), text))
+}
+
+/// The statements of `src` before the first one that doesn't parse, so the
+/// statement being typed leaves the ones before it known.
+fn parsed_prefix(src: &str) -> Vec<Stmt<'_>> {
+    let mut end = src.len();
+    loop {
+        let error = match SrcParser::parse(Rule::source, &src[..end]) {
+            Ok(pairs) => return SrcParser.source(pairs).unwrap_or_default(),
+            Err(error) => error,
+        };
+        let at = match error.location {
+            InputLocation::Pos(at) => at,
+            InputLocation::Span((at, _)) => at,
+        };
+        // cut back to the end of the last statement before the error, each
+        // round ends earlier than the one before
+        match src[..at.min(end.saturating_sub(1))].rfind(';') {
+            Some(semicolon) => end = semicolon + 1,
+            None => return vec![],
+        }
+    }
+}
+
+/// Every name known at the end of `src`: the builtins of the environment and
+/// whatever the script defines, followed by the keywords. While a statement
+/// doesn't parse, the names defined before it are still there.
+pub fn completions(src: &str) -> Vec<CompletionItem> {
+    let source = parsed_prefix(src);
+    let mut checker = StaticChecker::default();
+    checker.check(&source);
+
+    let environment = &checker.environment;
+    let mut names: Vec<_> = environment.lookup.iter().collect();
+    names.sort_by_key(|(name, _)| name.as_str());
+    let mut items: Vec<CompletionItem> = names
+        .into_iter()
+        .map(|(name, ty)| {
+            let (kind, detail) = match ty {
+                IdentTy::Func => (
+                    CompletionItemKind::FUNCTION,
+                    environment
+                        .arity(name)
+                        .map(|arity| format!("takes {} argument(s)", arity)),
+                ),
+                IdentTy::Const => (
+                    CompletionItemKind::CONSTANT,
+                    environment
+                        .constants
+                        .get(name)
+                        .map(|value| format!("= {}", value)),
+                ),
+                IdentTy::Var => (CompletionItemKind::VARIABLE, None),
+            };
+            CompletionItem {
+                label: name.clone(),
+                kind: Some(kind),
+                detail,
+                ..CompletionItem::default()
+            }
+        })
+        .collect();
+    items.extend(KEYWORDS.iter().map(|keyword| CompletionItem {
+        label: keyword.to_string(),
+        kind: Some(CompletionItemKind::KEYWORD),
+        ..CompletionItem::default()
+    }));
+    items
+}
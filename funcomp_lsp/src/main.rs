@@ -0,0 +1,193 @@
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, HoverRequest, Request as RequestTrait, SemanticTokensFullRequest,
+};
+use lsp_types::{
+    CompletionOptions, CompletionParams, CompletionResponse, DidChangeTextDocumentParams,
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, MarkupContent, MarkupKind, PublishDiagnosticsParams, SemanticTokens,
+    SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions, SemanticTokensParams,
+    SemanticTokensResult, SemanticTokensServerCapabilities, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+};
+use std::collections::HashMap;
+use std::error::Error;
+
+mod analysis;
+mod tokens;
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+            SemanticTokensOptions {
+                legend: SemanticTokensLegend {
+                    token_types: tokens::LEGEND.to_vec(),
+                    token_modifiers: vec![],
+                },
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..SemanticTokensOptions::default()
+            },
+        )),
+        ..ServerCapabilities::default()
+    }
+}
+
+/// The open documents, the client sends them whole on every change.
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Uri, String>,
+}
+
+impl Server {
+    fn request(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            HoverRequest::METHOD => self.hover(request),
+            Completion::METHOD => self.completion(request),
+            SemanticTokensFullRequest::METHOD => self.semantic_tokens(request),
+            method => {
+                return Response::new_err(
+                    id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("unsupported request {}", method),
+                )
+            }
+        };
+        match result {
+            Ok(result) => Response {
+                id,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string()),
+        }
+    }
+
+    fn document(&self, uri: &Uri) -> &str {
+        self.documents.get(uri).map_or("", String::as_str)
+    }
+
+    fn hover(&self, request: Request) -> Result<serde_json::Value> {
+        let (_, params) = request.extract::<HoverParams>(HoverRequest::METHOD)?;
+        let position = params.text_document_position_params;
+        let src = self.document(&position.text_document.uri);
+        let hover = analysis::offset(src, position.position)
+            .and_then(|offset| analysis::hover(src, offset))
+            .map(|(range, value)| Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: Some(range),
+            });
+        Ok(serde_json::to_value(hover)?)
+    }
+
+    fn completion(&self, request: Request) -> Result<serde_json::Value> {
+        let (_, params) = request.extract::<CompletionParams>(Completion::METHOD)?;
+        let src = self.document(&params.text_document_position.text_document.uri);
+        let items = analysis::completions(src);
+        Ok(serde_json::to_value(CompletionResponse::Array(items))?)
+    }
+
+    fn semantic_tokens(&self, request: Request) -> Result<serde_json::Value> {
+        let (_, params) =
+            request.extract::<SemanticTokensParams>(SemanticTokensFullRequest::METHOD)?;
+        let src = self.document(&params.text_document.uri);
+        let data = tokens::encode(src, &tokens::tokens(src));
+        Ok(serde_json::to_value(SemanticTokensResult::Tokens(
+            SemanticTokens {
+                result_id: None,
+                data,
+            },
+        ))?)
+    }
+
+    /// Keeps the documents up to date, returns the diagnostics to publish.
+    fn notification(&mut self, notification: Notification) -> Result<Option<Notification>> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams =
+                    notification.extract(DidOpenTextDocument::METHOD)?;
+                let document = params.text_document;
+                self.documents.insert(document.uri.clone(), document.text);
+                document.uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams =
+                    notification.extract(DidChangeTextDocument::METHOD)?;
+                // with full sync the last change is the whole document
+                let Some(change) = params.content_changes.into_iter().last() else {
+                    return Ok(None);
+                };
+                let uri = params.text_document.uri;
+                self.documents.insert(uri.clone(), change.text);
+                uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    notification.extract(DidCloseTextDocument::METHOD)?;
+                self.documents.remove(&params.text_document.uri);
+                // clears what was published for it
+                return Ok(Some(publish(params.text_document.uri, vec![])));
+            }
+            _ => return Ok(None),
+        };
+        let diagnostics = analysis::diagnostics(self.document(&uri));
+        Ok(Some(publish(uri, diagnostics)))
+    }
+}
+
+fn publish(uri: Uri, diagnostics: Vec<lsp_types::Diagnostic>) -> Notification {
+    Notification::new(
+        PublishDiagnostics::METHOD.into(),
+        PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        },
+    )
+}
+
+fn run(connection: &Connection) -> Result<()> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    let mut server = Server::default();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let response = server.request(request);
+                connection.sender.send(response.into())?;
+            }
+            Message::Notification(notification) => {
+                match server.notification(notification) {
+                    Ok(Some(notification)) => connection.sender.send(notification.into())?,
+                    Ok(None) => {}
+                    // a notification has no reply to carry the error
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    run(&connection)?;
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
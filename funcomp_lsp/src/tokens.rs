@@ -0,0 +1,159 @@
+use crate::analysis::KEYWORDS;
+use lsp_types::{SemanticToken, SemanticTokenType};
+
+/// The token types in the order of [`TokenKind`], sent to the client once.
+pub const LEGEND: &[SemanticTokenType] = &[
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::OPERATOR,
+    SemanticTokenType::COMMENT,
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TokenKind {
+    Keyword,
+    Function,
+    Variable,
+    Parameter,
+    Number,
+    Operator,
+    Comment,
+}
+
+/// A token of the source as a byte range.
+#[derive(Debug)]
+pub struct Token {
+    pub start: usize,
+    pub end: usize,
+    pub kind: TokenKind,
+}
+
+/// Where the lexer is inside a `fn` statement, so that its params are told
+/// apart in the body.
+enum Scope<'src> {
+    Outside,
+    Name,
+    Params(Vec<&'src str>),
+    Body(Vec<&'src str>),
+}
+
+/// Splits `src` into tokens without parsing it, so that a script that is
+/// being edited, and doesn't parse, still gets highlighted.
+///
+/// A name followed by `(` is a func, as is the name after `fn`.
+pub fn tokens(src: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut scope = Scope::Outside;
+    let mut at = 0;
+    while let Some(c) = src[at..].chars().next() {
+        let rest = &src[at..];
+        let start = at;
+        let kind = if c.is_whitespace() {
+            at += c.len_utf8();
+            continue;
+        } else if rest.starts_with("//") || rest.starts_with("--") {
+            at += rest.find('\n').unwrap_or(rest.len());
+            TokenKind::Comment
+        } else if c.is_ascii_digit() {
+            at += digits(rest);
+            if rest[at - start..].starts_with('.') && digits(&rest[at - start + 1..]) > 0 {
+                at += 1 + digits(&rest[at - start + 1..]);
+            }
+            TokenKind::Number
+        } else if c.is_ascii_alphabetic() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            at += len;
+            let word = &rest[..len];
+            if KEYWORDS.contains(&word.to_ascii_lowercase().as_str()) {
+                if word.eq_ignore_ascii_case("fn") {
+                    scope = Scope::Name;
+                }
+                TokenKind::Keyword
+            } else {
+                match &mut scope {
+                    Scope::Name => {
+                        scope = Scope::Params(vec![]);
+                        TokenKind::Function
+                    }
+                    Scope::Params(params) => {
+                        params.push(word);
+                        TokenKind::Parameter
+                    }
+                    Scope::Body(params) if params.contains(&word) => TokenKind::Parameter,
+                    _ if src[at..].trim_start().starts_with('(') => TokenKind::Function,
+                    _ => TokenKind::Variable,
+                }
+            }
+        } else {
+            at += c.len_utf8();
+            match c {
+                ')' => {
+                    if let Scope::Params(params) = &mut scope {
+                        scope = Scope::Body(std::mem::take(params));
+                    }
+                    continue;
+                }
+                ';' => {
+                    scope = Scope::Outside;
+                    continue;
+                }
+                '*' if rest[1..].starts_with('*') => {
+                    at += 1;
+                    TokenKind::Operator
+                }
+                '<' | '>' | '=' | '!' if rest[1..].starts_with('=') => {
+                    at += 1;
+                    TokenKind::Operator
+                }
+                '+' | '-' | '*' | '/' | '%' | '^' | '<' | '>' | '=' => TokenKind::Operator,
+                _ => continue,
+            }
+        };
+        tokens.push(Token {
+            start,
+            end: at,
+            kind,
+        });
+    }
+    tokens
+}
+
+fn digits(src: &str) -> usize {
+    src.find(|c: char| !c.is_ascii_digit()).unwrap_or(src.len())
+}
+
+/// Encodes `tokens` relative to each other, the way LSP sends them.
+pub fn encode(src: &str, tokens: &[Token]) -> Vec<SemanticToken> {
+    let mut encoded = vec![];
+    // line and UTF-16 column of the start of the previous token
+    let (mut line, mut column) = (0, 0);
+    let mut at = 0;
+    for token in tokens {
+        let skipped = &src[at..token.start];
+        let (token_line, token_column) = match skipped.rfind('\n') {
+            Some(newline) => (
+                line + skipped.matches('\n').count() as u32,
+                skipped[newline + 1..].encode_utf16().count() as u32,
+            ),
+            None => (line, column + skipped.encode_utf16().count() as u32),
+        };
+        encoded.push(SemanticToken {
+            delta_line: token_line - line,
+            delta_start: if token_line == line {
+                token_column - column
+            } else {
+                token_column
+            },
+            length: src[token.start..token.end].encode_utf16().count() as u32,
+            token_type: token.kind as u32,
+            token_modifiers_bitset: 0,
+        });
+        (line, column, at) = (token_line, token_column, token.start);
+    }
+    encoded
+}
@@ -0,0 +1,203 @@
+//! Drives `funcomp-lsp` over stdio the way an editor would.
+
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+const URI: &str = "file:///petal.fc";
+
+const SCRIPT: &str = "\
+// petal
+fn petal(a, k) = Cos(k * a) * Sin(PI / 2);
+for T from 0 to 2 * PI step 0.01 draw (petal(T, 3), T);
+rot is T;
+";
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: i64,
+}
+
+impl Client {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_funcomp-lsp"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("funcomp-lsp starts");
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Self {
+            child,
+            stdin,
+            stdout,
+            next_id: 1,
+        }
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            self.stdout.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length: ") {
+                length = value.parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}));
+        loop {
+            let message = self.receive();
+            if message["id"] == id {
+                assert!(
+                    message.get("error").is_none(),
+                    "{} failed: {}",
+                    method,
+                    message
+                );
+                return message["result"].clone();
+            }
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({"jsonrpc": "2.0", "method": method, "params": params}));
+    }
+
+    /// Waits for the next diagnostics the server publishes.
+    fn diagnostics(&mut self) -> Vec<Value> {
+        loop {
+            let message = self.receive();
+            if message["method"] == "textDocument/publishDiagnostics" {
+                assert_eq!(message["params"]["uri"], URI);
+                return message["params"]["diagnostics"].as_array().unwrap().clone();
+            }
+        }
+    }
+
+    fn hover(&mut self, line: u32, character: u32) -> String {
+        let hover = self.request(
+            "textDocument/hover",
+            json!({
+                "textDocument": {"uri": URI},
+                "position": {"line": line, "character": character},
+            }),
+        );
+        hover["contents"]["value"].as_str().unwrap().to_string()
+    }
+}
+
+#[test]
+fn session() {
+    let mut client = Client::start();
+    let result = client.request("initialize", json!({"capabilities": {}}));
+    let capabilities = &result["capabilities"];
+    assert_eq!(capabilities["hoverProvider"], true);
+    assert!(capabilities["completionProvider"].is_object());
+    let legend = &capabilities["semanticTokensProvider"]["legend"]["tokenTypes"];
+    assert_eq!(legend[0], "keyword");
+    client.notify("initialized", json!({}));
+
+    client.notify(
+        "textDocument/didOpen",
+        json!({"textDocument": {"uri": URI, "languageId": "funcomp", "version": 1, "text": SCRIPT}}),
+    );
+    let diagnostics = client.diagnostics();
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert_eq!(diagnostics[0]["severity"], 1);
+    assert!(diagnostics[0]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Expect a Const in Rot"));
+    assert_eq!(
+        diagnostics[0]["range"],
+        json!({"start": {"line": 3, "character": 7}, "end": {"line": 3, "character": 8}})
+    );
+
+    // right after `Sin(PI / 2)`, `T` in the draw, `petal`, and `a` in its body
+    let hovers = [
+        (1, 41, "Sin(PI / 2)\n```\n\nConst"),
+        (2, 52, "T\n```\n\nVar"),
+        (1, 5, "petal\n```\n\nCallable, takes 2 argument(s)"),
+        (1, 25, "a\n```\n\nConst"),
+    ];
+    for (line, character, expected) in hovers {
+        let hover = client.hover(line, character);
+        assert!(hover.ends_with(expected), "{:?}", hover);
+    }
+
+    let completions = client.request(
+        "textDocument/completion",
+        json!({"textDocument": {"uri": URI}, "position": {"line": 4, "character": 0}}),
+    );
+    let labels: Vec<&str> = completions
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["label"].as_str().unwrap())
+        .collect();
+    for label in ["Sin", "PI", "T", "petal", "draw"] {
+        assert!(
+            labels.contains(&label),
+            "{} missing from {:?}",
+            label,
+            labels
+        );
+    }
+
+    let tokens = client.request(
+        "textDocument/semanticTokens/full",
+        json!({"textDocument": {"uri": URI}}),
+    );
+    let data: Vec<u64> = tokens["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|value| value.as_u64().unwrap())
+        .collect();
+    // the comment, then `fn` a line below, then `petal` as a func
+    assert_eq!(data[..15], [0, 0, 8, 6, 0, 1, 0, 2, 0, 0, 0, 3, 5, 1, 0]);
+
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": {"uri": URI, "version": 2},
+            "contentChanges": [{"text": SCRIPT.replace("rot is T;", "rot is PI")}],
+        }),
+    );
+    let diagnostics = client.diagnostics();
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert_eq!(diagnostics[0]["range"]["start"]["line"], 4);
+
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": {"uri": URI, "version": 3},
+            "contentChanges": [{"text": SCRIPT.replace("rot is T;", "rot is PI;")}],
+        }),
+    );
+    assert!(client.diagnostics().is_empty());
+
+    client.request("shutdown", Value::Null);
+    client.notify("exit", Value::Null);
+    assert!(client.child.wait().unwrap().success());
+}
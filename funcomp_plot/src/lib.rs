@@ -1,30 +1,317 @@
 use plotters::coord::types::RangedCoordf32;
+use plotters::element::DashedPathElement;
 pub use plotters::prelude::*;
 
 #[macro_export]
 macro_rules! draw {
-    ($root: expr, $elems: expr) => {{
-        let dot = |x: f32, y: f32| {
-            EmptyElement::at((x, y)) + Circle::new((0, 0), 1, ShapeStyle::from(&RED).filled())
-        };
+    ($root: expr, $elems: expr) => {
+        $crate::draw!($root, $elems, &$crate::Pen::default())
+    };
+    ($root: expr, $elems: expr, $pen: expr) => {
+        $crate::stroke(
+            &$root,
+            $elems
+                .into_iter()
+                .map(|elem| (elem.0 as f32, elem.1 as f32)),
+            $pen,
+        )
+    };
+}
+
+/// How the samples of one draw statement are put on the canvas.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Style {
+    /// A dot per sample.
+    #[default]
+    Dots,
+    /// Consecutive samples joined by a solid line.
+    Line,
+    /// Consecutive samples joined by a dashed line.
+    Dashed,
+}
+
+impl std::fmt::Display for Style {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Style::Dots => write!(f, "dots"),
+            Style::Line => write!(f, "line"),
+            Style::Dashed => write!(f, "dashed"),
+        }
+    }
+}
+
+/// `size` is the dot radius or the line width, in device pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pen {
+    pub color: RGBColor,
+    pub size: f32,
+    pub style: Style,
+}
+
+impl Default for Pen {
+    fn default() -> Self {
+        Self {
+            color: RED,
+            size: 1.,
+            style: Style::Dots,
+        }
+    }
+}
+
+impl Pen {
+    fn width(&self) -> u32 {
+        (self.size.round() as u32).max(1)
+    }
+}
+
+fn is_finite((x, y): (f32, f32)) -> bool {
+    x.is_finite() && y.is_finite()
+}
+
+/// Splits `points` into the runs between non-finite samples, so a path
+/// through a pole such as `Tan(T)` is left open instead of jumping across
+/// the canvas.
+pub fn finite_runs(points: impl IntoIterator<Item = (f32, f32)>) -> Vec<Vec<(f32, f32)>> {
+    let mut runs = vec![];
+    let mut run = vec![];
+    for point in points {
+        if is_finite(point) {
+            run.push(point);
+        } else if !run.is_empty() {
+            runs.push(std::mem::take(&mut run));
+        }
+    }
+    if !run.is_empty() {
+        runs.push(run);
+    }
+    runs
+}
 
-        for elem in $elems {
-            $root.draw(&dot(elem.0 as f32, elem.1 as f32)).unwrap();
+/// Pulls `value` into a band a hundred times as wide as `range` around it,
+/// far enough to stay off-canvas but close enough not to overflow the
+/// backend's pixel coordinates.
+fn clamp_to(value: f32, range: std::ops::Range<f32>) -> f32 {
+    let reach = (range.end - range.start).abs() * 100.;
+    value.clamp(range.start - reach, range.end + reach)
+}
+
+/// Draws `points` onto `root` the way `pen` says. Non-finite samples are
+/// skipped and break connected styles into separate paths.
+pub fn stroke<DB: DrawingBackend>(
+    root: &Plot<DB>,
+    points: impl IntoIterator<Item = (f32, f32)>,
+    pen: &Pen,
+) -> PlotResult<DB, ()> {
+    let (x_range, y_range) = (root.get_x_range(), root.get_y_range());
+    // only finite points are clamped, clamping an infinite one would draw it
+    let clamp = |(x, y): (f32, f32)| (clamp_to(x, x_range.clone()), clamp_to(y, y_range.clone()));
+    let width = pen.width();
+    let style = ShapeStyle::from(&pen.color);
+    let dot =
+        |(x, y): (f32, f32)| EmptyElement::at((x, y)) + Circle::new((0, 0), width, style.filled());
+    if pen.style == Style::Dots {
+        return points
+            .into_iter()
+            .filter(|point| is_finite(*point))
+            .try_for_each(|point| root.draw(&dot(clamp(point))));
+    }
+    let line = style.stroke_width(width);
+    finite_runs(points).into_iter().try_for_each(|run| {
+        let run: Vec<_> = run.into_iter().map(clamp).collect();
+        if let [point] = run[..] {
+            // a lone sample between two gaps has no segment to show it
+            return root.draw(&dot(point));
+        }
+        match pen.style {
+            Style::Dashed => root.draw(&DashedPathElement::new(
+                run,
+                4 * width as i32,
+                3 * width as i32,
+                line,
+            )),
+            _ => root.draw(&PathElement::new(run, line)),
         }
-    }};
+    })
 }
 
-pub type Draw<'a> = DrawingArea<BitMapBackend<'a>, Cartesian2d<RangedCoordf32, RangedCoordf32>>;
+/// A drawing area in the logical coordinates of a [`Canvas`], on any backend.
+pub type Plot<DB> = DrawingArea<DB, Cartesian2d<RangedCoordf32, RangedCoordf32>>;
 
-pub fn get_drawing_area(
-    path: &str,
-) -> DrawingArea<BitMapBackend, Cartesian2d<RangedCoordf32, RangedCoordf32>> {
-    let root = BitMapBackend::new(path, (640, 480)).into_drawing_area();
-    root.fill(&RGBColor(250, 250, 250)).unwrap();
+pub type PlotResult<DB, T> = Result<T, DrawingAreaErrorKind<<DB as DrawingBackend>::ErrorType>>;
+
+pub type Draw<'a> = Plot<BitMapBackend<'a>>;
+
+pub type DrawResult<'a, T> = PlotResult<BitMapBackend<'a>, T>;
+
+pub type SvgDraw<'a> = Plot<SVGBackend<'a>>;
+
+/// The kind of image a canvas is written out as.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Format {
+    #[default]
+    Png,
+    Svg,
+}
+
+impl Format {
+    /// Picks the format from the extension of `path`, falling back to PNG.
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("svg") => Format::Svg,
+            _ => Format::Png,
+        }
+    }
 
-    root.apply_coord_spec(Cartesian2d::<RangedCoordf32, RangedCoordf32>::new(
-        0f32..640f32,
-        0f32..480f32,
-        (20..620, 20..460),
-    ))
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Svg => "svg",
+        }
+    }
+}
+
+/// Size and look of the image a script is rendered into.
+///
+/// `size` and `margin` are in logical units, which is also the coordinate
+/// space scripts draw in; `dpi_scale` multiplies them into device pixels, so
+/// the same script renders at a higher resolution without being rewritten.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Canvas {
+    pub size: (u32, u32),
+    pub dpi_scale: f32,
+    pub background: RGBColor,
+    pub margin: u32,
+}
+
+impl Default for Canvas {
+    fn default() -> Self {
+        Self {
+            size: (640, 480),
+            dpi_scale: 1.,
+            background: RGBColor(250, 250, 250),
+            margin: 20,
+        }
+    }
+}
+
+/// Most device pixels an image may have, so a large canvas or DPI scale
+/// fails with an error instead of exhausting memory.
+pub const MAX_PIXELS: u64 = 1 << 26;
+
+impl Canvas {
+    /// Size of the output image in device pixels.
+    pub fn pixel_size(&self) -> (u32, u32) {
+        (self.scaled(self.size.0), self.scaled(self.size.1))
+    }
+
+    /// `pen` with its size, given in logical units, turned into device pixels.
+    ///
+    /// The size is capped at the width plus the height of the image: from
+    /// anywhere on it, a larger pen covers nothing more, only takes longer.
+    pub fn scale_pen(&self, pen: &Pen) -> Pen {
+        let (width, height) = self.pixel_size();
+        Pen {
+            size: (pen.size * self.dpi_scale).min(width as f32 + height as f32),
+            ..*pen
+        }
+    }
+
+    /// Whether there is room left to plot in once the margins are taken off.
+    pub fn is_drawable(&self) -> bool {
+        self.dpi_scale.is_finite()
+            && self.dpi_scale > 0.
+            && self.size.0 > 2 * self.margin
+            && self.size.1 > 2 * self.margin
+    }
+
+    /// Whether the output image stays within [`MAX_PIXELS`].
+    pub fn fits(&self) -> bool {
+        let (width, height) = self.pixel_size();
+        width as u64 * height as u64 <= MAX_PIXELS
+    }
+
+    fn scaled(&self, len: u32) -> u32 {
+        (len as f32 * self.dpi_scale).round() as u32
+    }
+}
+
+pub fn get_drawing_area<'a, P: AsRef<std::path::Path> + ?Sized>(
+    path: &'a P,
+    canvas: &Canvas,
+) -> DrawResult<'a, Draw<'a>> {
+    plot_area(BitMapBackend::new(path, canvas.pixel_size()), canvas)
+}
+
+pub fn get_svg_area<'a, P: AsRef<std::path::Path> + ?Sized>(
+    path: &'a P,
+    canvas: &Canvas,
+) -> PlotResult<SVGBackend<'a>, SvgDraw<'a>> {
+    plot_area(SVGBackend::new(path, canvas.pixel_size()), canvas)
+}
+
+/// Fills `backend` with the canvas background and maps the canvas' logical
+/// coordinates onto it, inside the margins.
+pub fn plot_area<DB: DrawingBackend>(backend: DB, canvas: &Canvas) -> PlotResult<DB, Plot<DB>> {
+    let (width, height) = canvas.pixel_size();
+    let margin = canvas.scaled(canvas.margin) as i32;
+    let root = backend.into_drawing_area();
+    root.fill(&canvas.background)?;
+
+    Ok(
+        root.apply_coord_spec(Cartesian2d::<RangedCoordf32, RangedCoordf32>::new(
+            0f32..canvas.size.0 as f32,
+            0f32..canvas.size.1 as f32,
+            (
+                margin..width as i32 - margin,
+                margin..height as i32 - margin,
+            ),
+        )),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SVG of `points` stroked with `style` on the default canvas.
+    fn svg(points: &[(f32, f32)], style: Style) -> String {
+        let canvas = Canvas::default();
+        let mut out = String::new();
+        {
+            let backend = SVGBackend::with_string(&mut out, canvas.pixel_size());
+            let root = plot_area(backend, &canvas).unwrap();
+            let pen = Pen {
+                style,
+                ..Pen::default()
+            };
+            stroke(&root, points.iter().copied(), &pen).unwrap();
+            root.present().unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn leaves_a_rotated_pole_open() {
+        // `1 / T` around 0 under `rot is 0.5`, the pole turns both coordinates
+        // infinite, or NaN where the rotation multiplies it by 0
+        let points = [
+            (100., 50.),
+            (200., 100.),
+            (f32::NEG_INFINITY, f32::INFINITY),
+            (f32::NAN, f32::INFINITY),
+            (300., 300.),
+            (400., 350.),
+        ];
+        let line = svg(&points, Style::Line);
+        assert_eq!(line.matches("<polyline").count(), 2, "{}", line);
+        let dots = svg(&points, Style::Dots);
+        assert_eq!(dots.matches("<circle").count(), 4, "{}", dots);
+        for out in [line, dots] {
+            assert!(
+                !out.contains("640,480") && !out.contains("cx=\"640\""),
+                "{}",
+                out
+            );
+        }
+    }
 }
@@ -1,10 +1,9 @@
-use funcomp_plot::{draw, get_drawing_area};
-use plotters::prelude::*;
+use funcomp_plot::{draw, get_drawing_area, Canvas};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let root = get_drawing_area("out.png");
+    let root = get_drawing_area("out.png", &Canvas::default())?;
 
-    draw!(root, [(0, 0)]);
+    draw!(root, [(0, 0)])?;
     root.present()?;
     Ok(())
 }